@@ -0,0 +1,22 @@
+-- Create Newsletter Issues Table
+-- Every published issue is persisted so that delivery can be carried out (and resumed) in the background.
+CREATE TABLE newsletter_issues (
+	newsletter_issue_id uuid NOT NULL,
+	title TEXT NOT NULL,
+	text_content TEXT NOT NULL,
+	html_content TEXT NOT NULL,
+	published_at timestamptz NOT NULL,
+	PRIMARY KEY (newsletter_issue_id)
+);
+
+-- Create Issue Delivery Queue Table
+-- One row per (issue, recipient) pair still waiting to be delivered.
+-- Rows are removed by the delivery worker once the email has been sent.
+CREATE TABLE issue_delivery_queue (
+	newsletter_issue_id uuid NOT NULL
+		REFERENCES newsletter_issues (newsletter_issue_id),
+	subscriber_email TEXT NOT NULL,
+	n_retries SMALLINT NOT NULL DEFAULT 0,
+	execute_after timestamptz NOT NULL DEFAULT now(),
+	PRIMARY KEY (newsletter_issue_id, subscriber_email)
+);
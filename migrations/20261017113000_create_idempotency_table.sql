@@ -0,0 +1,16 @@
+-- Create Idempotency Table
+-- Stores the first response returned for each `Idempotency-Key`, so that retries can be replayed.
+-- Response columns are NULL while the first request is still being processed.
+CREATE TYPE header_pair AS (
+	name TEXT,
+	value BYTEA
+);
+
+CREATE TABLE idempotency (
+	idempotency_key TEXT NOT NULL,
+	response_status_code SMALLINT NULL,
+	response_headers header_pair[] NULL,
+	response_body BYTEA NULL,
+	created_at timestamptz NOT NULL,
+	PRIMARY KEY (idempotency_key)
+);
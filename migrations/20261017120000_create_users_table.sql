@@ -0,0 +1,9 @@
+-- Create Users Table
+-- Operator accounts allowed to use the admin endpoints.
+-- Passwords are stored as Argon2id hashes in PHC string format (algorithm, parameters and salt included).
+CREATE TABLE users (
+	user_id uuid NOT NULL,
+	username TEXT NOT NULL UNIQUE,
+	password_hash TEXT NOT NULL,
+	PRIMARY KEY (user_id)
+);
@@ -0,0 +1,8 @@
+-- Seed the first operator account: username `admin`, password `everythinghastostartsomewhere`.
+-- Change the password right after the first deployment.
+INSERT INTO users (user_id, username, password_hash)
+VALUES (
+	'ddf8994f-d522-4659-8d02-c1d479057be6',
+	'admin',
+	'$argon2id$v=19$m=15000,t=2,p=1$QFD2mCJSTPR8d6ADucM5ZQ$lmk/xRuYzH+Z6yZSpD5xSAPbrkjJZIY2gtYY+pzeIMI'
+);
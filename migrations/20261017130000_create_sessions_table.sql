@@ -0,0 +1,8 @@
+-- Create Sessions Table
+-- Server-side storage for the admin sessions: the cookie only carries the (random) session key.
+CREATE TABLE sessions (
+	session_key TEXT NOT NULL,
+	session_state TEXT NOT NULL,
+	expires_at timestamptz NOT NULL,
+	PRIMARY KEY (session_key)
+);
@@ -0,0 +1,12 @@
+-- Add Scheduling To Newsletter Issues
+-- An issue with a `scheduled_at` date is enqueued by the scheduler once that date is reached.
+-- `enqueued_at` records when the delivery tasks were created: an issue is only ever enqueued once,
+-- and it can no longer be rescheduled or cancelled afterwards.
+ALTER TABLE newsletter_issues ADD COLUMN scheduled_at timestamptz NULL;
+ALTER TABLE newsletter_issues ADD COLUMN enqueued_at timestamptz NULL;
+
+-- Issues published before scheduling existed were enqueued right away
+UPDATE newsletter_issues SET enqueued_at = published_at;
+
+CREATE INDEX newsletter_issues_due_idx ON newsletter_issues (scheduled_at)
+	WHERE enqueued_at IS NULL;
@@ -0,0 +1,24 @@
+-- Add Status To Newsletter Issues
+-- Lifecycle of an issue: draft -> scheduled -> sending -> sent.
+-- A draft can be sent right away, a scheduled issue can go back to draft until it is enqueued.
+ALTER TABLE newsletter_issues ADD COLUMN status TEXT NULL;
+BEGIN;
+	UPDATE newsletter_issues
+		SET status = CASE
+			WHEN enqueued_at IS NULL AND scheduled_at IS NULL THEN 'draft'
+			WHEN enqueued_at IS NULL THEN 'scheduled'
+			WHEN EXISTS (
+				SELECT 1 FROM issue_delivery_queue
+				WHERE issue_delivery_queue.newsletter_issue_id = newsletter_issues.newsletter_issue_id
+			) THEN 'sending'
+			ELSE 'sent'
+		END
+		WHERE status IS NULL;
+	ALTER TABLE newsletter_issues ALTER COLUMN status SET NOT NULL;
+	ALTER TABLE newsletter_issues ADD CONSTRAINT newsletter_issues_status_check
+		CHECK (status IN ('draft', 'scheduled', 'sending', 'sent'));
+COMMIT;
+
+DROP INDEX newsletter_issues_due_idx;
+CREATE INDEX newsletter_issues_due_idx ON newsletter_issues (scheduled_at)
+	WHERE status = 'scheduled';
@@ -0,0 +1,20 @@
+-- Create Issue Deliveries Table
+-- Delivery log: one row per (issue, recipient) pair, kept after the queue entry is gone.
+-- `status` is 'pending' until the worker is done with the recipient, then 'sent', 'failed'
+-- (the provider kept refusing the email) or 'skipped' (the recipient was no longer eligible).
+CREATE TABLE issue_deliveries (
+	newsletter_issue_id uuid NOT NULL
+		REFERENCES newsletter_issues (newsletter_issue_id),
+	subscriber_email TEXT NOT NULL,
+	status TEXT NOT NULL DEFAULT 'pending'
+		CHECK (status IN ('pending', 'sent', 'failed', 'skipped')),
+	n_attempts INTEGER NOT NULL DEFAULT 0,
+	last_error TEXT NULL,
+	provider_message_id TEXT NULL,
+	updated_at timestamptz NOT NULL DEFAULT now(),
+	PRIMARY KEY (newsletter_issue_id, subscriber_email)
+);
+
+-- Deliveries still in the queue when the log was introduced
+INSERT INTO issue_deliveries (newsletter_issue_id, subscriber_email, n_attempts)
+SELECT newsletter_issue_id, subscriber_email, n_retries FROM issue_delivery_queue;
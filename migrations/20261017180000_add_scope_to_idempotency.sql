@@ -0,0 +1,7 @@
+-- Scope idempotency keys to the endpoint and to the caller
+-- The same key sent to another endpoint, or by another operator, is a different request.
+-- Keys saved before this change get an empty scope: they are never replayed again.
+ALTER TABLE idempotency ADD COLUMN scope TEXT NOT NULL DEFAULT '';
+ALTER TABLE idempotency ALTER COLUMN scope DROP DEFAULT;
+ALTER TABLE idempotency DROP CONSTRAINT idempotency_pkey;
+ALTER TABLE idempotency ADD PRIMARY KEY (scope, idempotency_key);
@@ -0,0 +1,11 @@
+-- Create Unsubscribe Tokens Table
+-- One random token per subscriber, embedded in the unsubscribe link of every issue.
+-- Existing subscribers get a token right away, so that their next issue has a working link.
+CREATE TABLE unsubscribe_tokens(
+	unsubscribe_token TEXT NOT NULL,
+	subscriber_id uuid NOT NULL UNIQUE
+		REFERENCES subscriptions (id) ON DELETE CASCADE,
+	PRIMARY KEY (unsubscribe_token)
+);
+INSERT INTO unsubscribe_tokens (unsubscribe_token, subscriber_id)
+SELECT replace(gen_random_uuid()::text, '-', ''), id FROM subscriptions;
@@ -0,0 +1,6 @@
+-- The seeded `admin` account had a password published in the repository.
+-- Remove it unless its password has been changed since: the first operator is now created
+-- at startup (see `create_first_operator`).
+DELETE FROM users
+WHERE user_id = 'ddf8994f-d522-4659-8d02-c1d479057be6'
+	AND password_hash = '$argon2id$v=19$m=15000,t=2,p=1$QFD2mCJSTPR8d6ADucM5ZQ$lmk/xRuYzH+Z6yZSpD5xSAPbrkjJZIY2gtYY+pzeIMI';
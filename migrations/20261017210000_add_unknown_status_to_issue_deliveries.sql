@@ -0,0 +1,5 @@
+-- Deliveries whose outcome is unknown: the request timed out (or its answer could not be read)
+-- and the provider may have accepted the email. They are not sent again, to avoid duplicates.
+ALTER TABLE issue_deliveries DROP CONSTRAINT issue_deliveries_status_check;
+ALTER TABLE issue_deliveries ADD CONSTRAINT issue_deliveries_status_check
+	CHECK (status IN ('pending', 'sent', 'failed', 'skipped', 'unknown'));
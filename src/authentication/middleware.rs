@@ -0,0 +1,187 @@
+use crate::authentication::{validate_credentials, AuthError, Credentials};
+use crate::session_state::TypedSession;
+use crate::utils::see_other;
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::HeaderMap;
+use actix_web::middleware::Next;
+use actix_web::{web, FromRequest, HttpMessage, HttpResponse};
+use anyhow::Context;
+use base64::Engine;
+use secrecy::{ExposeSecret, SecretString};
+use sqlx::PgPool;
+use std::ops::Deref;
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+/// Id of the authenticated operator, available to handlers through `web::ReqData<UserId>`
+#[derive(Copy, Clone, Debug)]
+pub struct UserId(Uuid);
+
+impl std::fmt::Display for UserId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Deref for UserId {
+    type Target = Uuid;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Middleware rejecting requests that do not carry valid operator credentials
+/// (HTTP Basic authentication scheme) with a `401 Unauthorized`.
+pub async fn reject_invalid_credentials(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let credentials = match basic_authentication(req.headers()) {
+        Ok(credentials) => credentials,
+        Err(e) => {
+            tracing::warn!("Rejected a request without valid credentials: {:?}", e);
+            return Ok(req.into_response(unauthorized()).map_into_right_body());
+        }
+    };
+
+    let pool = req
+        .app_data::<web::Data<PgPool>>()
+        .expect("The database pool is not registered as application data")
+        .clone();
+    match validate_credentials(credentials, &pool).await {
+        Ok(user_id) => {
+            req.extensions_mut().insert(UserId(user_id));
+            next.call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        }
+        Err(AuthError::InvalidCredentials(e)) => {
+            tracing::warn!("Rejected a request with invalid credentials: {:?}", e);
+            Ok(req.into_response(unauthorized()).map_into_right_body())
+        }
+        Err(AuthError::UnexpectedError(e)) => {
+            tracing::error!("Failed to validate credentials: {:?}", e);
+            Ok(req
+                .into_response(HttpResponse::InternalServerError().finish())
+                .map_into_right_body())
+        }
+    }
+}
+
+/// Credentials that a third party (e.g. the email provider) must present to call our webhooks.
+/// They come from the configuration, not from the `users` table.
+pub struct WebhookCredentials(pub Credentials);
+
+/// Middleware rejecting webhook calls that do not carry the configured `WebhookCredentials`
+/// (HTTP Basic authentication scheme) with a `401 Unauthorized`.
+pub async fn reject_invalid_webhook_credentials(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let expected = req
+        .app_data::<web::Data<WebhookCredentials>>()
+        .expect("The webhook credentials are not registered as application data");
+    let is_valid = match basic_authentication(req.headers()) {
+        Ok(credentials) => {
+            let username = credentials
+                .username
+                .as_bytes()
+                .ct_eq(expected.0.username.as_bytes());
+            let password = credentials
+                .password
+                .expose_secret()
+                .as_bytes()
+                .ct_eq(expected.0.password.expose_secret().as_bytes());
+            bool::from(username & password)
+        }
+        Err(e) => {
+            tracing::warn!(error.cause_chain = ?e, "Missing webhook credentials");
+            false
+        }
+    };
+    if !is_valid {
+        tracing::warn!("Rejected a webhook call with invalid credentials");
+        return Ok(req
+            .into_response(unauthorized_with_realm("webhooks"))
+            .map_into_right_body());
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+/// Middleware redirecting requests without a logged-in operator session to the login page.
+pub async fn reject_anonymous_users(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let session = {
+        let (http_request, payload) = req.parts_mut();
+        TypedSession::from_request(http_request, payload).await
+    }?;
+
+    match session.get_user_id() {
+        Ok(Some(user_id)) => {
+            req.extensions_mut().insert(UserId(user_id));
+            next.call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        }
+        Ok(None) => Ok(req.into_response(see_other("/login")).map_into_right_body()),
+        Err(e) => {
+            tracing::error!("Failed to read the user id from the session: {:?}", e);
+            Ok(req
+                .into_response(HttpResponse::InternalServerError().finish())
+                .map_into_right_body())
+        }
+    }
+}
+
+fn unauthorized() -> HttpResponse {
+    unauthorized_with_realm("publish")
+}
+
+fn unauthorized_with_realm(realm: &'static str) -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header((
+            actix_web::http::header::WWW_AUTHENTICATE,
+            format!(r#"Basic realm="{}""#, realm),
+        ))
+        .finish()
+}
+
+/// Extract the credentials from an `Authorization: Basic <base64(username:password)>` header.
+fn basic_authentication(headers: &HeaderMap) -> Result<Credentials, anyhow::Error> {
+    // The header value, if present, must be a valid UTF8 string
+    let header_value = headers
+        .get("Authorization")
+        .context("The 'Authorization' header was missing")?
+        .to_str()
+        .context("The 'Authorization' header was not a valid UTF8 string.")?;
+    let base64encoded_segment = header_value
+        .strip_prefix("Basic ")
+        .context("The authorization scheme was not 'Basic'.")?;
+    let decoded_bytes = base64::engine::general_purpose::STANDARD
+        .decode(base64encoded_segment)
+        .context("Failed to base64-decode 'Basic' credentials.")?;
+    let decoded_credentials = String::from_utf8(decoded_bytes)
+        .context("The decoded credential string is not valid UTF8.")?;
+
+    // Split into two segments, using ':' as delimiter
+    let mut credentials = decoded_credentials.splitn(2, ':');
+    let username = credentials
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("A username must be provided in 'Basic' auth."))?
+        .to_string();
+    let password = credentials
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("A password must be provided in 'Basic' auth."))?
+        .to_string();
+
+    Ok(Credentials {
+        username,
+        password: SecretString::from(password),
+    })
+}
@@ -0,0 +1,11 @@
+mod middleware;
+mod password;
+
+pub use middleware::{
+    reject_anonymous_users, reject_invalid_credentials, reject_invalid_webhook_credentials, UserId,
+    WebhookCredentials,
+};
+pub use password::{
+    change_password, compute_password_hash, create_first_operator, validate_credentials, AuthError,
+    Credentials,
+};
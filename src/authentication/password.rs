@@ -0,0 +1,195 @@
+use crate::telemetry::spawn_blocking_with_tracing;
+use anyhow::Context;
+use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use secrecy::{ExposeSecret, SecretString};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(thiserror::Error, Debug)]
+pub enum AuthError {
+    #[error("Invalid credentials.")]
+    InvalidCredentials(#[source] anyhow::Error),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+pub struct Credentials {
+    pub username: String,
+    pub password: SecretString,
+}
+
+#[tracing::instrument(name = "Get stored credentials", skip(username, pool))]
+async fn get_stored_credentials(
+    username: &str,
+    pool: &PgPool,
+) -> Result<Option<(Uuid, SecretString)>, anyhow::Error> {
+    let row = sqlx::query!(
+        r#"SELECT user_id, password_hash FROM users WHERE username = $1"#,
+        username,
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to perform a query to retrieve stored credentials.")?
+    .map(|row| (row.user_id, SecretString::from(row.password_hash)));
+    Ok(row)
+}
+
+/// Check the provided credentials against the `users` table, returning the matching user id.
+///
+/// We always verify a hash, even when the username does not exist, so that the response time
+/// does not leak which usernames are registered.
+#[tracing::instrument(name = "Validate credentials", skip(credentials, pool))]
+pub async fn validate_credentials(
+    credentials: Credentials,
+    pool: &PgPool,
+) -> Result<Uuid, AuthError> {
+    let mut user_id = None;
+    let mut expected_password_hash = SecretString::from(
+        "$argon2id$v=19$m=15000,t=2,p=1$\
+        gZiV/M1gPc22ElAH/Jh1Hw$\
+        CWOrkoo7oJBQ/iyh7uJ0LO2aLEfrHwTWllSAxT0zRno",
+    );
+
+    if let Some((stored_user_id, stored_password_hash)) =
+        get_stored_credentials(&credentials.username, pool).await?
+    {
+        user_id = Some(stored_user_id);
+        expected_password_hash = stored_password_hash;
+    }
+
+    // Hashing is CPU-bound: move it off the async executor so it doesn't stall the actix workers
+    spawn_blocking_with_tracing(move || {
+        verify_password_hash(expected_password_hash, credentials.password)
+    })
+    .await
+    .context("Failed to spawn blocking task.")??;
+
+    // This is only set to `Some` if we found credentials in the store.
+    // So, even if the default password ends up matching (somehow) with the provided password,
+    // we never authenticate a non-existing user.
+    user_id
+        .ok_or_else(|| anyhow::anyhow!("Unknown username."))
+        .map_err(AuthError::InvalidCredentials)
+}
+
+#[tracing::instrument(
+    name = "Verify password hash",
+    skip(expected_password_hash, password_candidate)
+)]
+fn verify_password_hash(
+    expected_password_hash: SecretString,
+    password_candidate: SecretString,
+) -> Result<(), AuthError> {
+    let expected_password_hash = PasswordHash::new(expected_password_hash.expose_secret())
+        .context("Failed to parse hash in PHC string format.")?;
+
+    // The hashing parameters are read from the PHC string, not from `Argon2::default()`
+    Argon2::default()
+        .verify_password(
+            password_candidate.expose_secret().as_bytes(),
+            &expected_password_hash,
+        )
+        .context("Invalid password.")
+        .map_err(AuthError::InvalidCredentials)
+}
+
+/// Replace the password of an operator, hashing it with the same parameters as every other account.
+#[tracing::instrument(name = "Change password", skip(password, pool))]
+pub async fn change_password(
+    user_id: Uuid,
+    password: SecretString,
+    pool: &PgPool,
+) -> Result<(), anyhow::Error> {
+    let password_hash = spawn_blocking_with_tracing(move || compute_password_hash(password))
+        .await?
+        .context("Failed to hash password")?;
+    sqlx::query!(
+        r#"UPDATE users SET password_hash = $1 WHERE user_id = $2"#,
+        password_hash.expose_secret(),
+        user_id
+    )
+    .execute(pool)
+    .await
+    .context("Failed to change user's password in the database.")?;
+    Ok(())
+}
+
+/// Create the first operator account if there is none yet, so that a fresh deployment
+/// can be administered without a credential shared through the repository.
+///
+/// Without a configured password, a random one is generated and printed once to stderr:
+/// change it right after the first login. Replicas starting together create a single account.
+#[tracing::instrument(name = "Create the first operator", skip(password, pool))]
+pub async fn create_first_operator(
+    username: &str,
+    password: Option<SecretString>,
+    pool: &PgPool,
+) -> Result<(), anyhow::Error> {
+    let has_operator = sqlx::query_scalar!(r#"SELECT EXISTS (SELECT 1 FROM users) as "exists!""#)
+        .fetch_one(pool)
+        .await
+        .context("Failed to look for existing operators.")?;
+    if has_operator {
+        return Ok(());
+    }
+    let generated = password.is_none();
+    let password = password.unwrap_or_else(|| {
+        let mut rng = rand::thread_rng();
+        let password: String = std::iter::repeat_with(|| rng.sample(Alphanumeric))
+            .map(char::from)
+            .take(32)
+            .collect();
+        SecretString::from(password)
+    });
+    let hashed = password.clone();
+    let password_hash = spawn_blocking_with_tracing(move || compute_password_hash(hashed))
+        .await?
+        .context("Failed to hash password")?;
+    let created = sqlx::query!(
+        r#"INSERT INTO users (user_id, username, password_hash)
+        SELECT $1, $2, $3
+        WHERE NOT EXISTS (SELECT 1 FROM users)
+        ON CONFLICT (username) DO NOTHING
+        "#,
+        Uuid::new_v4(),
+        username,
+        password_hash.expose_secret()
+    )
+    .execute(pool)
+    .await
+    .context("Failed to store the first operator in the database.")?
+    .rows_affected()
+        == 1;
+    if created && generated {
+        // Printed once to stderr, never through tracing: logs get shipped and kept around
+        tracing::warn!(
+            "Created the operator account `{}` with a generated password, printed to stderr",
+            username
+        );
+        eprintln!(
+            "Created the operator account `{}` with the generated password `{}`: \
+            change it from /admin/password right away.",
+            username,
+            password.expose_secret()
+        );
+    } else if created {
+        tracing::info!("Created the operator account `{}`", username);
+    }
+    Ok(())
+}
+
+/// Hash a password with Argon2id, returning it in PHC string format.
+pub fn compute_password_hash(password: SecretString) -> Result<SecretString, anyhow::Error> {
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    let password_hash = Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        Params::new(15000, 2, 1, None).unwrap(),
+    )
+    .hash_password(password.expose_secret().as_bytes(), &salt)?
+    .to_string();
+    Ok(SecretString::from(password_hash))
+}
@@ -1,6 +1,11 @@
 use std::time::Duration;
 
+use crate::authentication::Credentials;
 use crate::domain::SubscriberEmail;
+use crate::email_client::{
+    CircuitBreaker, EmailClient, FileEmailSender, PostmarkEmailSender, RateLimiter, RetryPolicy,
+    SmtpEmailSender,
+};
 use secrecy::{ExposeSecret, SecretString};
 use serde_aux::field_attributes::deserialize_number_from_string;
 use sqlx::postgres::{PgConnectOptions, PgSslMode};
@@ -19,6 +24,40 @@ pub struct ApplicationSettings {
     pub port: u16,
     pub host: String,
     pub base_url: String,
+    // Secret used to sign the session and flash message cookies (at least 64 bytes long).
+    // Only local.yaml has one: elsewhere, set APP_APPLICATION__HMAC_SECRET.
+    pub hmac_secret: SecretString,
+    // How long a confirmation link stays valid
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub subscription_token_ttl_hours: u64,
+    // How long we keep subscribers who never confirmed, and expired confirmation links
+    // (still answered with a 410), before deleting them
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub unconfirmed_subscriber_retention_hours: u64,
+    // Directory of the email templates. Missing templates fall back to compiled-in defaults.
+    pub templates_directory: String,
+    // How often the scheduler looks for newsletter issues due to be sent
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub issue_scheduler_interval_seconds: u64,
+    // Operator account created at startup when there is none yet. Without a password
+    // (e.g. APP_APPLICATION__INITIAL_OPERATOR_PASSWORD), a random one is generated and
+    // printed to stderr.
+    pub initial_operator_username: String,
+    pub initial_operator_password: Option<SecretString>,
+}
+
+impl ApplicationSettings {
+    pub fn subscription_token_ttl(&self) -> Duration {
+        Duration::from_secs(self.subscription_token_ttl_hours * 60 * 60)
+    }
+
+    pub fn unconfirmed_subscriber_retention(&self) -> Duration {
+        Duration::from_secs(self.unconfirmed_subscriber_retention_hours * 60 * 60)
+    }
+
+    pub fn issue_scheduler_interval(&self) -> Duration {
+        Duration::from_secs(self.issue_scheduler_interval_seconds)
+    }
 }
 
 #[derive(serde::Deserialize, Clone)]
@@ -35,10 +74,77 @@ pub struct DatabaseSettings {
 
 #[derive(serde::Deserialize, Clone)]
 pub struct EmailClientSettings {
+    // Which backend delivers the emails (Postmark when omitted)
+    #[serde(default)]
+    pub backend: EmailBackend,
     pub base_url: String,
     pub sender_email: String,
     pub authorization_token: SecretString,
     pub timeout_milliseconds: u64,
+    // Retries of transient failures (connection errors, 429, 5xx). 1 attempt means no retries.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_attempts: u32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub retry_base_delay_milliseconds: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub retry_max_delay_milliseconds: u64,
+    // Also retry timeouts (and unreadable answers). The provider may have accepted the email
+    // already: a retry can deliver it twice. When off, such deliveries are logged as `unknown`.
+    pub retry_timeouts: bool,
+    // Stop calling the provider after this many consecutive transient failures...
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub circuit_breaker_failure_threshold: u32,
+    // ...and try again once this cooldown has elapsed
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub circuit_breaker_cooldown_milliseconds: u64,
+    // Provider quota: sustained rate, and how many emails can go out at once after
+    // a quiet period. Each replica keeps its own token bucket, in memory...
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub rate_limit_messages_per_second: f64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub rate_limit_burst: u32,
+    // ...with an equal share of the quota (the burst is rounded down): keep this in line
+    // with the number of replicas running the application, or they send faster than
+    // the provider allows
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub rate_limit_replicas: u32,
+    // Emails waiting longer than this for the bucket are not sent: the caller gives up,
+    // rather than holding its database locks for the duration
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub rate_limit_max_wait_milliseconds: u64,
+    // Credentials Postmark must send (HTTP Basic authentication) when calling our webhooks
+    pub webhook_username: String,
+    pub webhook_password: SecretString,
+    // Required with the `smtp` backend
+    pub smtp: Option<SmtpSettings>,
+    // Required with the `file` backend
+    pub file: Option<FileSettings>,
+}
+
+#[derive(serde::Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailBackend {
+    #[default]
+    Postmark,
+    Smtp,
+    File,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct SmtpSettings {
+    pub host: String,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<SecretString>,
+    // Upgrade the connection with STARTTLS. Only disable it for local SMTP sinks.
+    pub starttls: bool,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct FileSettings {
+    // Directory receiving one `.eml` file per email
+    pub directory: String,
 }
 
 impl EmailClientSettings {
@@ -49,6 +155,99 @@ impl EmailClientSettings {
     pub fn timeout(&self) -> Duration {
         Duration::from_millis(self.timeout_milliseconds)
     }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.max_attempts,
+            base_delay: Duration::from_millis(self.retry_base_delay_milliseconds),
+            max_delay: Duration::from_millis(self.retry_max_delay_milliseconds),
+            retry_timeouts: self.retry_timeouts,
+        }
+    }
+
+    pub fn webhook_credentials(&self) -> Credentials {
+        Credentials {
+            username: self.webhook_username.clone(),
+            password: self.webhook_password.clone(),
+        }
+    }
+
+    /// Build an `EmailClient` delivering through the configured backend
+    pub fn client(&self) -> Result<EmailClient, String> {
+        let sender_email = self.sender()?;
+        let client = match self.backend {
+            EmailBackend::Postmark => EmailClient::new(
+                sender_email,
+                PostmarkEmailSender::new(
+                    self.base_url.clone(),
+                    self.authorization_token.clone(),
+                    self.timeout(),
+                ),
+            ),
+            EmailBackend::Smtp => {
+                let smtp = self
+                    .smtp
+                    .as_ref()
+                    .expect("The smtp email backend requires an `email_client.smtp` section.");
+                let credentials = smtp.username.clone().map(|username| {
+                    let password = smtp
+                        .password
+                        .clone()
+                        .unwrap_or_else(|| SecretString::from(""));
+                    (username, password)
+                });
+                let transport = SmtpEmailSender::new(
+                    &smtp.host,
+                    smtp.port,
+                    credentials,
+                    smtp.starttls,
+                    self.timeout(),
+                )
+                .expect("Failed to set up the SMTP transport.");
+                EmailClient::new(sender_email, transport)
+            }
+            EmailBackend::File => {
+                let file = self
+                    .file
+                    .as_ref()
+                    .expect("The file email backend requires an `email_client.file` section.");
+                let transport = FileEmailSender::new(&file.directory)
+                    .expect("Failed to create the directory receiving the emails.");
+                EmailClient::new(sender_email, transport)
+            }
+        };
+        Ok(client
+            .with_retry_policy(self.retry_policy())
+            .with_circuit_breaker(CircuitBreaker::new(
+                self.circuit_breaker_failure_threshold,
+                Duration::from_millis(self.circuit_breaker_cooldown_milliseconds),
+            ))
+            .with_rate_limiter(self.rate_limiter()?))
+    }
+
+    /// Token bucket of this replica: its share of the provider quota.
+    ///
+    /// The burst is rounded down, so that the replicas together never exceed the quota,
+    /// but every replica can send at least one email at once: with fewer burst emails
+    /// than replicas, the replicas together can burst past the quota.
+    pub fn rate_limiter(&self) -> Result<RateLimiter, String> {
+        let rate = self.rate_limit_messages_per_second;
+        if !rate.is_finite() || rate <= 0.0 {
+            return Err(format!(
+                "The email rate limit must be positive, got {} messages per second",
+                rate
+            ));
+        }
+        if self.rate_limit_burst == 0 {
+            return Err("The email burst limit must be positive".into());
+        }
+        let replicas = self.rate_limit_replicas.max(1);
+        Ok(RateLimiter::new(
+            rate / f64::from(replicas),
+            (self.rate_limit_burst / replicas).max(1),
+            Duration::from_millis(self.rate_limit_max_wait_milliseconds),
+        ))
+    }
 }
 
 impl DatabaseSettings {
@@ -190,3 +389,44 @@ impl TryFrom<String> for Environment {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::get_configuration;
+
+    #[test]
+    fn every_replica_gets_an_equal_share_of_the_rate_limit() {
+        let mut settings = get_configuration().unwrap().email_client;
+        settings.rate_limit_messages_per_second = 10.0;
+        settings.rate_limit_burst = 20;
+        settings.rate_limit_replicas = 3;
+
+        let limiter = settings.rate_limiter().unwrap();
+
+        // Rounded down: together, the replicas stay within the quota
+        assert_eq!(limiter.burst(), 6);
+        assert_eq!(limiter.messages_per_second(), 10.0 / 3.0);
+    }
+
+    #[test]
+    fn every_replica_can_send_at_least_one_email_at_once() {
+        let mut settings = get_configuration().unwrap().email_client;
+        settings.rate_limit_burst = 2;
+        settings.rate_limit_replicas = 3;
+
+        let limiter = settings.rate_limiter().unwrap();
+
+        assert_eq!(limiter.burst(), 1);
+    }
+
+    #[test]
+    fn a_rate_limit_of_zero_is_rejected() {
+        let mut settings = get_configuration().unwrap().email_client;
+        settings.rate_limit_messages_per_second = 0.0;
+        assert!(settings.rate_limiter().is_err());
+
+        settings.rate_limit_messages_per_second = 10.0;
+        settings.rate_limit_burst = 0;
+        assert!(settings.rate_limiter().is_err());
+    }
+}
@@ -0,0 +1,273 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// State of the circuit breaker protecting the email provider
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Everything is fine: emails go through
+    Closed,
+    /// The provider keeps failing: emails fail fast, without calling it
+    Open,
+    /// The cooldown is over: a single trial email is let through to probe the provider
+    HalfOpen,
+}
+
+impl std::fmt::Display for CircuitState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        };
+        f.write_str(state)
+    }
+}
+
+/// Circuit breaker: opens after `failure_threshold` consecutive failures, fails fast while
+/// open, and lets a trial request through once `cooldown` has elapsed. The trial closes
+/// the circuit if it succeeds and opens it again if it fails.
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    trial_in_flight: bool,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold,
+            cooldown,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        let inner = self.inner.lock().unwrap();
+        self.state_of(&inner)
+    }
+
+    fn state_of(&self, inner: &Inner) -> CircuitState {
+        match inner.opened_at {
+            None => CircuitState::Closed,
+            Some(opened_at) if opened_at.elapsed() < self.cooldown => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+
+    /// A permit if a request to the provider may go out right now.
+    /// Its outcome is reported through the permit.
+    /// Otherwise, fails with how long is left until the cooldown is over (zero while
+    /// another trial request is in flight).
+    pub fn allow_request(&self) -> Result<CircuitPermit<'_>, Duration> {
+        let mut inner = self.inner.lock().unwrap();
+        let trial = match self.state_of(&inner) {
+            CircuitState::Closed => false,
+            CircuitState::Open => {
+                let opened_at = inner
+                    .opened_at
+                    .expect("An open circuit has an opening time");
+                return Err(self.cooldown.saturating_sub(opened_at.elapsed()));
+            }
+            // Only one trial at a time: the others keep failing fast
+            CircuitState::HalfOpen if inner.trial_in_flight => return Err(Duration::ZERO),
+            CircuitState::HalfOpen => {
+                tracing::info!(circuit_state = %CircuitState::HalfOpen, "Probing the email provider");
+                inner.trial_in_flight = true;
+                true
+            }
+        };
+        Ok(CircuitPermit {
+            breaker: self,
+            trial,
+        })
+    }
+
+    /// Only the trial request settles an open circuit: a request let through before it opened
+    /// says nothing about the provider after the cooldown.
+    fn record_success(&self, trial: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        if trial {
+            tracing::info!(circuit_state = %CircuitState::Closed, "The email provider is back: closing the circuit");
+            *inner = Inner::default();
+        } else if inner.opened_at.is_none() {
+            inner.consecutive_failures = 0;
+        }
+    }
+
+    fn record_failure(&self, trial: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        if !trial && inner.opened_at.is_some() {
+            return;
+        }
+        inner.consecutive_failures = inner.consecutive_failures.saturating_add(1);
+        if trial || inner.consecutive_failures >= self.failure_threshold {
+            tracing::warn!(
+                circuit_state = %CircuitState::Open,
+                consecutive_failures = inner.consecutive_failures,
+                "The email provider keeps failing: opening the circuit"
+            );
+            inner.opened_at = Some(Instant::now());
+            inner.trial_in_flight = false;
+        }
+    }
+}
+
+/// Permission to send a request to the provider, returned by `CircuitBreaker::allow_request`.
+///
+/// A trial permit dropped without an outcome (e.g. the request future was cancelled)
+/// gives up the trial, so that the next request can probe the provider.
+#[must_use]
+pub struct CircuitPermit<'a> {
+    breaker: &'a CircuitBreaker,
+    trial: bool,
+}
+
+impl CircuitPermit<'_> {
+    pub fn record_success(mut self) {
+        let trial = std::mem::take(&mut self.trial);
+        self.breaker.record_success(trial);
+    }
+
+    pub fn record_failure(mut self) {
+        let trial = std::mem::take(&mut self.trial);
+        self.breaker.record_failure(trial);
+    }
+}
+
+impl Drop for CircuitPermit<'_> {
+    fn drop(&mut self) {
+        if self.trial {
+            tracing::warn!("The trial request was cancelled before completing");
+            self.breaker.inner.lock().unwrap().trial_in_flight = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CircuitBreaker, CircuitState};
+    use std::time::Duration;
+
+    #[test]
+    fn the_circuit_opens_after_the_failure_threshold_is_reached() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(60));
+        breaker.record_failure(false);
+        breaker.record_failure(false);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.allow_request().is_ok());
+
+        breaker.record_failure(false);
+
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(breaker.allow_request().is_err());
+    }
+
+    #[test]
+    fn a_success_resets_the_consecutive_failures() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+        breaker.record_failure(false);
+        breaker.record_success(false);
+        breaker.record_failure(false);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn the_circuit_half_opens_after_the_cooldown_and_lets_a_single_trial_through() {
+        let breaker = CircuitBreaker::new(1, Duration::ZERO);
+        breaker.record_failure(false);
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+
+        let trial = breaker.allow_request();
+
+        assert!(trial.is_ok());
+        assert!(breaker.allow_request().is_err());
+    }
+
+    #[test]
+    fn a_successful_trial_closes_the_circuit() {
+        let breaker = CircuitBreaker::new(1, Duration::ZERO);
+        breaker.record_failure(false);
+        let trial = breaker.allow_request().unwrap();
+
+        trial.record_success();
+
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.allow_request().is_ok());
+    }
+
+    #[test]
+    fn a_failed_trial_opens_the_circuit_again() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(50));
+        breaker.record_failure(false);
+        std::thread::sleep(Duration::from_millis(60));
+        let trial = breaker.allow_request().unwrap();
+
+        trial.record_failure();
+
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(breaker.allow_request().is_err());
+    }
+
+    #[test]
+    fn an_open_circuit_tells_how_long_is_left_until_the_cooldown_is_over() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(60));
+        breaker.record_failure(false);
+
+        let retry_after = breaker.allow_request().err().unwrap();
+
+        assert!(retry_after > Duration::from_secs(59) && retry_after <= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn a_dropped_trial_lets_another_trial_through() {
+        let breaker = CircuitBreaker::new(1, Duration::ZERO);
+        breaker.record_failure(false);
+        let trial = breaker.allow_request().unwrap();
+
+        drop(trial);
+
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(breaker.allow_request().is_ok());
+    }
+
+    #[test]
+    fn a_request_let_through_before_the_circuit_opened_does_not_settle_the_trial() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(50));
+        let stale = breaker.allow_request().unwrap();
+        breaker.record_failure(false);
+        std::thread::sleep(Duration::from_millis(60));
+        let trial = breaker.allow_request().unwrap();
+
+        stale.record_failure();
+
+        // The trial is still the only request allowed through
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(breaker.allow_request().is_err());
+        trial.record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn a_late_success_of_a_request_sent_before_the_circuit_opened_does_not_close_it() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(50));
+        let stale = breaker.allow_request().unwrap();
+        breaker.record_failure(false);
+        std::thread::sleep(Duration::from_millis(60));
+        let trial = breaker.allow_request().unwrap();
+
+        stale.record_success();
+
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(breaker.allow_request().is_err());
+        trial.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+    }
+}
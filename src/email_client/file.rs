@@ -0,0 +1,80 @@
+use super::{mime_message, sent_mime_message, EmailError, EmailMessage, EmailSender, SentEmail};
+use lettre::{AsyncFileTransport, AsyncTransport, Tokio1Executor};
+use std::path::PathBuf;
+
+/// Backend writing every email as an `.eml` file in a directory, for local development.
+/// The files can be opened with any mail client.
+pub struct FileEmailSender {
+    transport: AsyncFileTransport<Tokio1Executor>,
+}
+
+impl FileEmailSender {
+    /// The directory is created if it does not exist yet
+    pub fn new(directory: impl Into<PathBuf>) -> Result<Self, std::io::Error> {
+        let directory = directory.into();
+        std::fs::create_dir_all(&directory)?;
+        Ok(Self {
+            transport: AsyncFileTransport::new(directory),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailSender for FileEmailSender {
+    async fn send(&self, message: &EmailMessage<'_>) -> Result<SentEmail, EmailError> {
+        let message = mime_message(message)?;
+        let sent = sent_mime_message(&message);
+        let id = self.transport.send(message).await?;
+        tracing::info!("Email written to {}.eml", id);
+        Ok(sent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FileEmailSender;
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{EmailHeader, EmailMessage, EmailSender};
+    use claim::assert_ok;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn send_writes_an_eml_file_with_both_parts_and_the_custom_headers() {
+        // Arrange
+        let directory = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let sender = FileEmailSender::new(&directory).unwrap();
+        let from = SubscriberEmail::parse("newsletter@example.com".into()).unwrap();
+        let to = SubscriberEmail::parse("ursula@example.com".into()).unwrap();
+
+        // Act
+        let outcome = sender
+            .send(&EmailMessage {
+                from: &from,
+                to: &to,
+                subject: "Issue #1",
+                html_body: "<p>Hello</p>",
+                text_body: "Hello",
+                headers: &[EmailHeader {
+                    name: "List-Unsubscribe",
+                    value: "<https://example.com/unsubscribe>",
+                }],
+            })
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+        let files: Vec<_> = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].extension().unwrap(), "eml");
+        let content = std::fs::read_to_string(&files[0]).unwrap();
+        assert!(content.contains("To: ursula@example.com"));
+        assert!(content.contains("Subject: Issue #1"));
+        assert!(content.contains("List-Unsubscribe: <https://example.com/unsubscribe>"));
+        assert!(content.contains("text/plain"));
+        assert!(content.contains("text/html"));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
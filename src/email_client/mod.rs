@@ -0,0 +1,1277 @@
+//! Outgoing emails.
+//!
+//! `EmailClient` is what the rest of the application talks to. The actual delivery is
+//! delegated to an `EmailSender` backend, selected in the configuration:
+//! Postmark's HTTP API, SMTP submission or `.eml` files written to a directory.
+mod circuit_breaker;
+mod file;
+mod postmark;
+mod rate_limiter;
+mod retry;
+mod smtp;
+
+pub use circuit_breaker::{CircuitBreaker, CircuitPermit, CircuitState};
+pub use file::FileEmailSender;
+pub use postmark::PostmarkEmailSender;
+pub use rate_limiter::{RateLimitError, RateLimiter, RateLimiterMetrics};
+pub use retry::RetryPolicy;
+pub use smtp::SmtpEmailSender;
+
+use crate::domain::SubscriberEmail;
+use crate::utils::error_chain_fmt;
+use lettre::message::header::{HeaderName, HeaderValue};
+use lettre::message::{Mailbox, MultiPart};
+use lettre::Message;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// An email backend: Postmark, SMTP, files, ...
+#[async_trait::async_trait]
+pub trait EmailSender: Send + Sync {
+    async fn send(&self, message: &EmailMessage<'_>) -> Result<SentEmail, EmailError>;
+
+    /// Send several emails at once and tell which ones were accepted.
+    ///
+    /// The default implementation sends the emails one by one.
+    async fn send_batch(&self, messages: &[EmailMessage<'_>]) -> BatchOutcome {
+        let mut outcome = BatchOutcome::default();
+        for message in messages {
+            match self.send(message).await {
+                Ok(sent) => outcome.sent.push((message.to.clone(), sent)),
+                Err(error) => outcome.failures.push(BatchFailure {
+                    recipients: vec![message.to.clone()],
+                    error,
+                }),
+            }
+        }
+        outcome
+    }
+}
+
+/// What the backend tells us about an accepted email
+#[derive(Debug, Default)]
+pub struct SentEmail {
+    /// Identifier of the message on the provider side (Postmark's `MessageID`, or the
+    /// `Message-ID` header for the MIME based backends), to match later events against it
+    pub message_id: Option<String>,
+}
+
+/// What happened to each email of a batch
+#[derive(Debug, Default)]
+pub struct BatchOutcome {
+    /// Recipients whose emails were accepted
+    pub sent: Vec<(SubscriberEmail, SentEmail)>,
+    /// An empty list means that every email was accepted
+    pub failures: Vec<BatchFailure>,
+}
+
+impl BatchOutcome {
+    /// Every email of the batch failed the same way
+    fn failed(messages: &[EmailMessage<'_>], error: EmailError) -> Self {
+        Self {
+            sent: Vec::new(),
+            failures: vec![BatchFailure {
+                recipients: messages.iter().map(|m| m.to.clone()).collect(),
+                error,
+            }],
+        }
+    }
+
+    fn extend(&mut self, other: BatchOutcome) {
+        self.sent.extend(other.sent);
+        self.failures.extend(other.failures);
+    }
+}
+
+/// Recipients of a batch whose emails were not accepted, and why.
+/// A failure can cover several recipients, e.g. when a whole request to the provider failed.
+/// With `EmailError::OutcomeUnknown`, the emails may have gone out: they must not be
+/// retried blindly.
+#[derive(Debug)]
+pub struct BatchFailure {
+    pub recipients: Vec<SubscriberEmail>,
+    pub error: EmailError,
+}
+
+/// One email of a `send_batch` call
+#[derive(Debug)]
+pub struct OutgoingEmail<'a> {
+    pub recipient: SubscriberEmail,
+    pub subject: &'a str,
+    pub html_content: &'a str,
+    pub text_content: &'a str,
+    pub headers: Vec<EmailHeader<'a>>,
+}
+
+/// Everything a backend needs to deliver an email
+#[derive(Debug)]
+pub struct EmailMessage<'a> {
+    pub from: &'a SubscriberEmail,
+    pub to: &'a SubscriberEmail,
+    pub subject: &'a str,
+    pub html_body: &'a str,
+    pub text_body: &'a str,
+    pub headers: &'a [EmailHeader<'a>],
+}
+
+/// A custom header added to an outgoing email
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct EmailHeader<'a> {
+    pub name: &'a str,
+    pub value: &'a str,
+}
+
+#[derive(thiserror::Error)]
+pub enum EmailError {
+    #[error("The email could not be turned into a valid message.")]
+    InvalidMessage(#[source] anyhow::Error),
+    #[error("The request to the Postmark API failed.")]
+    Postmark(#[from] reqwest::Error),
+    #[error("The email provider answered with a {status} status code.")]
+    UnsuccessfulStatus {
+        status: u16,
+        retry_after: Option<Duration>,
+    },
+    #[error("The email provider rejected the message (error code {error_code}): {message}")]
+    Rejected { error_code: i64, message: String },
+    #[error("The email provider sent back an unexpected response: {0}")]
+    UnexpectedResponse(String),
+    /// Nothing was sent: the provider can be tried again once `retry_after` has elapsed.
+    #[error("The email provider is unavailable: the circuit breaker is open.")]
+    CircuitOpen { retry_after: Duration },
+    /// The rate limiter would have kept the email waiting for too long: it was not sent.
+    #[error("The email rate limit is exhausted: the email could be sent in {retry_after:?}.")]
+    RateLimited { retry_after: Duration },
+    /// More emails at once than the rate limiter ever lets through: none was sent.
+    #[error("{requested} emails at once exceed the rate limit burst of {burst}.")]
+    ExceedsRateLimitBurst { requested: u32, burst: u32 },
+    /// The request may have reached the provider (it timed out, or the provider accepted it
+    /// but its answer could not be read): sending the email again could deliver it twice.
+    #[error("The email provider may or may not have accepted the message: {0}")]
+    OutcomeUnknown(String),
+    #[error("The SMTP submission failed.")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+    #[error("The email could not be written to a file.")]
+    File(#[from] lettre::transport::file::Error),
+}
+
+impl EmailError {
+    /// Transient failures (connection errors, rate limiting, provider outages) are worth
+    /// retrying. Anything else (e.g. an invalid recipient) would fail again.
+    /// Unknown outcomes (e.g. timeouts) are the exception: the provider may have accepted
+    /// the email and sending it again could deliver it twice. `RetryPolicy::retry_timeouts`
+    /// opts into retrying them anyway.
+    pub fn is_retryable(&self) -> bool {
+        self.is_transient() && !matches!(self, EmailError::OutcomeUnknown(_))
+    }
+
+    /// Failures pointing at the provider rather than at the email: they count against
+    /// the circuit breaker.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            // Timeouts are reported as `OutcomeUnknown`
+            EmailError::Postmark(e) => e.is_connect(),
+            EmailError::UnsuccessfulStatus { status, .. } => *status == 429 || *status >= 500,
+            EmailError::Smtp(e) => e.is_transient(),
+            // A provider which does not answer in time, or not in a readable way, is failing
+            EmailError::OutcomeUnknown(_) => true,
+            EmailError::InvalidMessage(_)
+            | EmailError::Rejected { .. }
+            | EmailError::UnexpectedResponse(_)
+            | EmailError::File(_)
+            // Failing fast is the whole point of an open circuit
+            | EmailError::CircuitOpen { .. }
+            // Our own limits: the provider has nothing to do with them
+            | EmailError::RateLimited { .. }
+            | EmailError::ExceedsRateLimitBurst { .. } => false,
+        }
+    }
+
+    /// How long to wait before trying again, if known: the provider may ask for it,
+    /// and our own rate limiter and circuit breaker know when emails can go out again
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            EmailError::UnsuccessfulStatus { retry_after, .. } => *retry_after,
+            EmailError::RateLimited { retry_after } | EmailError::CircuitOpen { retry_after } => {
+                Some(*retry_after)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Debug for EmailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+/// Clones are cheap and share the same transport, circuit breaker and rate limiter
+#[derive(Clone)]
+pub struct EmailClient {
+    sender: SubscriberEmail,
+    transport: Arc<dyn EmailSender>,
+    retry_policy: RetryPolicy,
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+impl EmailClient {
+    /// The client does not retry failed emails, unless a policy is set with `with_retry_policy`,
+    /// has no circuit breaker, unless one is set with `with_circuit_breaker`,
+    /// and is not rate limited, unless a limiter is set with `with_rate_limiter`.
+    pub fn new(sender: SubscriberEmail, transport: impl EmailSender + 'static) -> Self {
+        Self {
+            sender,
+            transport: Arc::new(transport),
+            retry_policy: RetryPolicy::no_retry(),
+            circuit_breaker: None,
+            rate_limiter: None,
+        }
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn with_circuit_breaker(mut self, circuit_breaker: CircuitBreaker) -> Self {
+        self.circuit_breaker = Some(Arc::new(circuit_breaker));
+        self
+    }
+
+    /// Every request to the provider (retries included) waits for a token of `rate_limiter`
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(Arc::new(rate_limiter));
+        self
+    }
+
+    /// Counters of the rate limiter, if there is one
+    pub fn rate_limiter_metrics(&self) -> Option<RateLimiterMetrics> {
+        self.rate_limiter.as_ref().map(|limiter| limiter.metrics())
+    }
+
+    async fn wait_for_rate_limiter(&self, n_messages: usize) -> Result<(), EmailError> {
+        if let Some(limiter) = &self.rate_limiter {
+            limiter
+                .acquire(u32::try_from(n_messages).unwrap_or(u32::MAX))
+                .await
+                .map_err(|e| match e {
+                    RateLimitError::Exhausted { retry_after } => {
+                        EmailError::RateLimited { retry_after }
+                    }
+                    RateLimitError::ExceedsBurst { requested, burst } => {
+                        EmailError::ExceedsRateLimitBurst { requested, burst }
+                    }
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Most emails a single request to the provider can carry without exceeding the
+    /// rate limiter burst (the transport may split them further)
+    fn max_chunk_size(&self) -> usize {
+        self.rate_limiter
+            .as_ref()
+            .map_or(usize::MAX, |limiter| limiter.burst() as usize)
+    }
+
+    /// State of the circuit breaker (always closed without a circuit breaker)
+    pub fn circuit_state(&self) -> CircuitState {
+        self.circuit_breaker
+            .as_ref()
+            .map_or(CircuitState::Closed, |breaker| breaker.state())
+    }
+
+    /// Send a single message through the circuit breaker.
+    /// Only transient failures count against the provider: a rejected recipient is our problem.
+    async fn send_through_circuit_breaker(
+        &self,
+        message: &EmailMessage<'_>,
+    ) -> Result<SentEmail, EmailError> {
+        let Some(breaker) = &self.circuit_breaker else {
+            self.wait_for_rate_limiter(1).await?;
+            return self.transport.send(message).await;
+        };
+        // Dropping the permit (e.g. when this future is cancelled) gives up a trial request
+        let permit = breaker
+            .allow_request()
+            .map_err(|retry_after| EmailError::CircuitOpen { retry_after })?;
+        self.wait_for_rate_limiter(1).await?;
+        let outcome = self.transport.send(message).await;
+        match &outcome {
+            Err(e) if e.is_transient() => permit.record_failure(),
+            _ => permit.record_success(),
+        }
+        outcome
+    }
+
+    pub async fn send_email(
+        &self,
+        recipient: SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+    ) -> Result<SentEmail, EmailError> {
+        self.send_email_with_headers(recipient, subject, html_content, text_content, &[])
+            .await
+    }
+
+    /// Same as `send_email`, adding custom headers (e.g. `List-Unsubscribe`) to the message
+    pub async fn send_email_with_headers(
+        &self,
+        recipient: SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        headers: &[EmailHeader<'_>],
+    ) -> Result<SentEmail, EmailError> {
+        let message = EmailMessage {
+            from: &self.sender,
+            to: &recipient,
+            subject,
+            html_body: html_content,
+            text_body: text_content,
+            headers,
+        };
+        let mut attempt = 1;
+        loop {
+            let error = match self.send_through_circuit_breaker(&message).await {
+                Ok(sent) => return Ok(sent),
+                Err(error) => error,
+            };
+            let Some(delay) = self.retry_policy.delay_before_retry(attempt, &error) else {
+                return Err(error);
+            };
+            tracing::warn!(
+                attempt,
+                retry_in_ms = delay.as_millis() as u64,
+                "Failed to send an email, retrying: {}",
+                error
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Send many emails at once (e.g. one newsletter issue to a list of subscribers).
+    /// The retry policy does not apply here: only the recipients listed in the returned
+    /// failures need to be retried, and that is up to the caller.
+    ///
+    /// With a rate limiter, the emails go out in chunks of at most its burst, each chunk
+    /// waiting for its own tokens: a large batch is spread over time instead of asking
+    /// for more tokens than the bucket can ever hold.
+    pub async fn send_batch(&self, emails: &[OutgoingEmail<'_>]) -> BatchOutcome {
+        let messages: Vec<_> = emails
+            .iter()
+            .map(|email| EmailMessage {
+                from: &self.sender,
+                to: &email.recipient,
+                subject: email.subject,
+                html_body: email.html_content,
+                text_body: email.text_content,
+                headers: &email.headers,
+            })
+            .collect();
+        let mut outcome = BatchOutcome::default();
+        for chunk in messages.chunks(self.max_chunk_size()) {
+            outcome.extend(self.send_chunk_through_circuit_breaker(chunk).await);
+        }
+        outcome
+    }
+
+    /// Send a chunk of a batch through the circuit breaker and the rate limiter
+    async fn send_chunk_through_circuit_breaker(
+        &self,
+        messages: &[EmailMessage<'_>],
+    ) -> BatchOutcome {
+        let Some(breaker) = &self.circuit_breaker else {
+            if let Err(e) = self.wait_for_rate_limiter(messages.len()).await {
+                return BatchOutcome::failed(messages, e);
+            }
+            return self.transport.send_batch(messages).await;
+        };
+        let permit = match breaker.allow_request() {
+            Ok(permit) => permit,
+            Err(retry_after) => {
+                return BatchOutcome::failed(messages, EmailError::CircuitOpen { retry_after })
+            }
+        };
+        if let Err(e) = self.wait_for_rate_limiter(messages.len()).await {
+            return BatchOutcome::failed(messages, e);
+        }
+        let outcome = self.transport.send_batch(messages).await;
+        if outcome
+            .failures
+            .iter()
+            .any(|failure| failure.error.is_transient())
+        {
+            permit.record_failure();
+        } else {
+            permit.record_success();
+        }
+        outcome
+    }
+}
+
+/// Build a MIME message (`multipart/alternative` with a text and an HTML part),
+/// shared by the backends speaking plain email rather than an HTTP API.
+fn mime_message(message: &EmailMessage<'_>) -> Result<Message, EmailError> {
+    let invalid_message = |e| EmailError::InvalidMessage(e);
+    let from: Mailbox = message
+        .from
+        .as_ref()
+        .parse()
+        .map_err(|e| invalid_message(anyhow::Error::new(e)))?;
+    let to: Mailbox = message
+        .to
+        .as_ref()
+        .parse()
+        .map_err(|e| invalid_message(anyhow::Error::new(e)))?;
+    let mut mime_message = Message::builder()
+        .from(from)
+        .to(to)
+        .subject(message.subject)
+        .message_id(None)
+        .multipart(MultiPart::alternative_plain_html(
+            message.text_body.to_owned(),
+            message.html_body.to_owned(),
+        ))
+        .map_err(|e| invalid_message(anyhow::Error::new(e)))?;
+    for header in message.headers {
+        let name = HeaderName::new_from_ascii(header.name.to_owned())
+            .map_err(|e| invalid_message(anyhow::anyhow!("{}: {}", header.name, e)))?;
+        mime_message
+            .headers_mut()
+            .insert_raw(HeaderValue::new(name, header.value.to_owned()));
+    }
+    Ok(mime_message)
+}
+
+/// Outcome of a MIME based delivery: the provider is identified by our own `Message-ID`
+fn sent_mime_message(message: &Message) -> SentEmail {
+    SentEmail {
+        message_id: message.headers().get_raw("Message-ID").map(str::to_owned),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{
+        CircuitBreaker, CircuitState, EmailClient, EmailError, EmailHeader, OutgoingEmail,
+        PostmarkEmailSender, RateLimiter, RetryPolicy,
+    };
+    use claim::{assert_err, assert_ok};
+    use fake::faker::internet::en::SafeEmail;
+    use fake::faker::lorem::en::{Paragraph, Sentence};
+    use fake::{Fake, Faker};
+    use secrecy::SecretString;
+    use wiremock::matchers::{any, body_partial_json, header, header_exists, method, path};
+    use wiremock::{Mock, MockServer, Request, ResponseTemplate};
+
+    // wiremock::MockServer is a full-blown HTTP server.
+    // MockServer::start() asks the OS for a random available port and spins up the server on a background thread, ready to listen for incoming requests.
+
+    struct SendEmailBodyMatcher;
+
+    impl wiremock::Match for SendEmailBodyMatcher {
+        fn matches(&self, request: &Request) -> bool {
+            // Try to parse the body as a JSON value
+            let result: Result<serde_json::Value, _> = serde_json::from_slice(&request.body);
+
+            if let Ok(body) = result {
+                dbg!(&body);
+                // Check that all the expected fields contain some values
+                body.get("From").is_some()
+                    && body.get("To").is_some()
+                    && body.get("Subject").is_some()
+                    && body.get("HtmlBody").is_some()
+                    && body.get("TextBody").is_some()
+            } else {
+                // If parsing failed, the body does not match
+                false
+            }
+        }
+    }
+
+    /// Answer a batch request with a successful result for each of its messages
+    struct AcceptEveryMessage;
+
+    impl wiremock::Respond for AcceptEveryMessage {
+        fn respond(&self, request: &Request) -> ResponseTemplate {
+            let messages: Vec<serde_json::Value> = serde_json::from_slice(&request.body).unwrap();
+            let results: Vec<_> = messages
+                .iter()
+                .map(|message| {
+                    serde_json::json!({
+                        "ErrorCode": 0,
+                        "Message": "OK",
+                        "MessageID": "b7bc2f4a-e38e-4336-af7d-e6c392c2f817",
+                        "To": message["To"]
+                    })
+                })
+                .collect();
+            ResponseTemplate::new(200).set_body_json(results)
+        }
+    }
+
+    /// Generate a random email subject
+    fn subject() -> String {
+        Sentence(1..2).fake()
+    }
+
+    /// Generate a random email content
+    fn content() -> String {
+        Paragraph(1..10).fake()
+    }
+
+    /// Generate a random subscriber email
+    fn email() -> SubscriberEmail {
+        SubscriberEmail::parse(SafeEmail().fake()).unwrap()
+    }
+
+    /// Get a test instance of EmailClient, backed by Postmark
+    fn email_client(base_url: String) -> EmailClient {
+        let timeout = Duration::from_millis(200);
+        let transport = PostmarkEmailSender::new(
+            base_url,
+            SecretString::from(Faker.fake::<String>()),
+            timeout,
+        );
+        EmailClient::new(email(), transport)
+    }
+
+    #[tokio::test]
+    async fn send_email_sends_the_expected_request() {
+        // Arrange
+
+        // Start a background HTTP server on a random local port
+        // Out of the box, MockServer returns 404 to all requests
+        // We can although instruct it to behave differently by mounting a Mock.
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        // Set up Wiremock to expect a request
+        // This is mounting a mock on the mock server
+        // MockServer receives a request, it will try to match it against the mounted mocks. Matching conditions for a mock are specified using Mock::given(...)
+        // Using "any()" matcher means that this mock will match any request
+        // When a request matches, the server will respond with the specified response template (200 OK in this case)
+        // Mock becomes effective only after it has been mounted on the server using "mount"
+        // Use "and" to chain multiple matchers
+        Mock::given(header_exists("X-Postmark-Server-Token"))
+            .and(header("Content-Type", "application/json"))
+            .and(path("/email"))
+            .and(method("POST"))
+            .and(SendEmailBodyMatcher)
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let _ = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        // Mock expectations are checked on drop
+        // MockServer verifies that the expectations specified on the mounted mocks are met when it is dropped
+        // In simple terms, if the expected number of requests were not received, the test will fail. In our case, we expected exactly one request to be received because of "expect(1)". Note we are calling "send_email" once above.
+    }
+
+    #[tokio::test]
+    async fn send_email_with_headers_sends_the_custom_headers() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(path("/email"))
+            .and(method("POST"))
+            .and(body_partial_json(serde_json::json!({
+                "Headers": [
+                    {"Name": "List-Unsubscribe", "Value": "<https://example.com/unsubscribe>"},
+                    {"Name": "List-Unsubscribe-Post", "Value": "List-Unsubscribe=One-Click"}
+                ]
+            })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email_with_headers(
+                email(),
+                &subject(),
+                &content(),
+                &content(),
+                &[
+                    EmailHeader {
+                        name: "List-Unsubscribe",
+                        value: "<https://example.com/unsubscribe>",
+                    },
+                    EmailHeader {
+                        name: "List-Unsubscribe-Post",
+                        value: "List-Unsubscribe=One-Click",
+                    },
+                ],
+            )
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+    }
+
+    /// Get a test instance of EmailClient retrying up to 3 times, with short delays
+    fn retrying_email_client(base_url: String) -> EmailClient {
+        email_client(base_url).with_retry_policy(RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_secs(2),
+            retry_timeouts: false,
+        })
+    }
+
+    /// Generate `n` emails to random recipients
+    fn outgoing_emails<'a>(n: usize, subject: &'a str, content: &'a str) -> Vec<OutgoingEmail<'a>> {
+        (0..n)
+            .map(|_| OutgoingEmail {
+                recipient: email(),
+                subject,
+                html_content: content,
+                text_content: content,
+                headers: vec![],
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn send_batch_sends_all_the_messages_in_a_single_request() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let (subject, content) = (subject(), content());
+        let emails = outgoing_emails(3, &subject, &content);
+
+        Mock::given(header_exists("X-Postmark-Server-Token"))
+            .and(path("/email/batch"))
+            .and(method("POST"))
+            .respond_with(AcceptEveryMessage)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client.send_batch(&emails).await;
+
+        // Assert
+        assert!(outcome.failures.is_empty());
+        assert_eq!(outcome.sent.len(), 3);
+        assert_eq!(outcome.sent[2].0.as_ref(), emails[2].recipient.as_ref());
+        assert_eq!(
+            outcome.sent[2].1.message_id.as_deref(),
+            Some("b7bc2f4a-e38e-4336-af7d-e6c392c2f817")
+        );
+        let requests = mock_server.received_requests().await.unwrap();
+        let body: Vec<serde_json::Value> = serde_json::from_slice(&requests[0].body).unwrap();
+        assert_eq!(body.len(), 3);
+        assert_eq!(body[1]["To"], emails[1].recipient.as_ref());
+    }
+
+    #[tokio::test]
+    async fn send_batch_splits_large_batches_in_chunks_of_500() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let (subject, content) = (subject(), content());
+        let emails = outgoing_emails(501, &subject, &content);
+
+        Mock::given(path("/email/batch"))
+            .and(method("POST"))
+            .respond_with(AcceptEveryMessage)
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let failures = email_client.send_batch(&emails).await.failures;
+
+        // Assert
+        assert!(failures.is_empty());
+        let requests = mock_server.received_requests().await.unwrap();
+        let sizes: Vec<usize> = requests
+            .iter()
+            .map(|r| {
+                serde_json::from_slice::<Vec<serde_json::Value>>(&r.body)
+                    .unwrap()
+                    .len()
+            })
+            .collect();
+        assert_eq!(sizes, vec![500, 1]);
+    }
+
+    #[tokio::test]
+    async fn send_batch_spreads_batches_beyond_what_the_rate_limiter_lets_through_at_once() {
+        // Arrange - 10 emails, while at most 2 + 100 * 0.05 = 7 could go out within max_wait
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri()).with_rate_limiter(RateLimiter::new(
+            100.0,
+            2,
+            Duration::from_millis(50),
+        ));
+        let (subject, content) = (subject(), content());
+        let emails = outgoing_emails(10, &subject, &content);
+
+        Mock::given(path("/email/batch"))
+            .and(method("POST"))
+            .respond_with(AcceptEveryMessage)
+            .expect(5)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let failures = email_client.send_batch(&emails).await.failures;
+
+        // Assert - one request per burst, each waiting for its own tokens
+        assert!(failures.is_empty());
+        let metrics = email_client.rate_limiter_metrics().unwrap();
+        assert_eq!(metrics.messages, 10);
+        assert_eq!(metrics.rejected, 0);
+    }
+
+    #[tokio::test]
+    async fn send_batch_reports_the_recipients_rejected_by_postmark() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let (subject, content) = (subject(), content());
+        let emails = outgoing_emails(2, &subject, &content);
+
+        Mock::given(path("/email/batch"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+                {"ErrorCode": 0, "Message": "OK"},
+                {"ErrorCode": 406, "Message": "You tried to send to a recipient that has been marked as inactive."}
+            ])))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client.send_batch(&emails).await;
+
+        // Assert
+        assert_eq!(outcome.sent.len(), 1);
+        assert_eq!(outcome.sent[0].0.as_ref(), emails[0].recipient.as_ref());
+        let failures = outcome.failures;
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].recipients.len(), 1);
+        assert_eq!(
+            failures[0].recipients[0].as_ref(),
+            emails[1].recipient.as_ref()
+        );
+        assert!(matches!(
+            failures[0].error,
+            EmailError::Rejected {
+                error_code: 406,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn send_batch_reports_every_recipient_if_the_request_is_refused() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let (subject, content) = (subject(), content());
+        let emails = outgoing_emails(3, &subject, &content);
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let failures = email_client.send_batch(&emails).await.failures;
+
+        // Assert
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].recipients.len(), 3);
+        assert!(matches!(
+            failures[0].error,
+            EmailError::UnsuccessfulStatus { status: 500, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn send_batch_reports_an_unknown_outcome_if_the_request_times_out() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let (subject, content) = (subject(), content());
+        let emails = outgoing_emails(3, &subject, &content);
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(180)))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let failures = email_client.send_batch(&emails).await.failures;
+
+        // Assert
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].recipients.len(), 3);
+        assert!(matches!(failures[0].error, EmailError::OutcomeUnknown(_)));
+    }
+
+    #[tokio::test]
+    async fn send_batch_reports_an_unknown_outcome_if_the_response_is_unreadable() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let (subject, content) = (subject(), content());
+        let emails = outgoing_emails(2, &subject, &content);
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_body_string("not json"))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let failures = email_client.send_batch(&emails).await.failures;
+
+        // Assert
+        assert_eq!(failures.len(), 1);
+        assert!(matches!(failures[0].error, EmailError::OutcomeUnknown(_)));
+    }
+
+    #[tokio::test]
+    async fn send_email_succeeds_if_the_server_returns_200() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        // Set up a bare minimum mock for the email client
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_returns_the_message_id_assigned_by_postmark() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "ErrorCode": 0,
+                "Message": "OK",
+                "MessageID": "b7bc2f4a-e38e-4336-af7d-e6c392c2f817",
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let sent = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await
+            .unwrap();
+
+        // Assert
+        assert_eq!(
+            sent.message_id.as_deref(),
+            Some("b7bc2f4a-e38e-4336-af7d-e6c392c2f817")
+        );
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_if_the_server_returns_500() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_times_out_if_the_server_takes_too_long() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        let response = ResponseTemplate::new(200).set_delay(Duration::from_secs(180)); // 3-minutes
+        Mock::given(any())
+            .respond_with(response)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert - Postmark may or may not have accepted the email
+        assert!(matches!(outcome, Err(EmailError::OutcomeUnknown(_))));
+    }
+
+    #[tokio::test]
+    async fn send_email_retries_after_a_server_error() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = retrying_email_client(mock_server.uri());
+
+        // The first request fails, the second one goes through
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_does_not_retry_after_a_timeout() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = retrying_email_client(mock_server.uri());
+
+        // Postmark may have accepted the first request: a retry could send the email twice
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(180)))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_retries_after_a_timeout_if_the_policy_allows_it() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri()).with_retry_policy(RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_secs(2),
+            retry_timeouts: true,
+        });
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(180)))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_gives_up_after_the_maximum_number_of_attempts() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = retrying_email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(3)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_does_not_retry_permanent_errors() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = retrying_email_client(mock_server.uri());
+
+        // Postmark answers with a 422 when the recipient is invalid
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(422))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_waits_as_long_as_retry_after_asks() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = retrying_email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "1"))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let start = std::time::Instant::now();
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_fast_once_the_circuit_is_open() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri())
+            .with_circuit_breaker(CircuitBreaker::new(2, Duration::from_secs(60)));
+
+        // Only the two requests opening the circuit reach the provider
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        for _ in 0..2 {
+            let _ = email_client
+                .send_email(email(), &subject(), &content(), &content())
+                .await;
+        }
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert!(matches!(outcome, Err(EmailError::CircuitOpen { .. })));
+        assert_eq!(email_client.circuit_state(), CircuitState::Open);
+    }
+
+    #[tokio::test]
+    async fn timeouts_count_against_the_circuit_breaker() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri())
+            .with_circuit_breaker(CircuitBreaker::new(1, Duration::from_secs(60)));
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(180)))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_err!(outcome);
+        assert_eq!(email_client.circuit_state(), CircuitState::Open);
+    }
+
+    #[tokio::test]
+    async fn permanent_errors_do_not_open_the_circuit() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri())
+            .with_circuit_breaker(CircuitBreaker::new(1, Duration::from_secs(60)));
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(422))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        for _ in 0..2 {
+            let _ = email_client
+                .send_email(email(), &subject(), &content(), &content())
+                .await;
+        }
+
+        // Assert
+        assert_eq!(email_client.circuit_state(), CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn a_cancelled_trial_does_not_keep_the_circuit_half_open() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri())
+            .with_circuit_breaker(CircuitBreaker::new(1, Duration::ZERO));
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .up_to_n_times(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(5)))
+            .up_to_n_times(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        let _ = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+        assert_eq!(email_client.circuit_state(), CircuitState::HalfOpen);
+
+        // Act - the trial is dropped while waiting for the provider
+        let (subject, content) = (subject(), content());
+        let trial = email_client.send_email(email(), &subject, &content, &content);
+        let _ = tokio::time::timeout(Duration::from_millis(100), trial).await;
+        let outcome = email_client
+            .send_email(email(), &subject, &content, &content)
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+        assert_eq!(email_client.circuit_state(), CircuitState::Closed);
+    }
+
+    #[tokio::test]
+    async fn clones_share_the_same_circuit_breaker() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri())
+            .with_circuit_breaker(CircuitBreaker::new(1, Duration::from_secs(60)));
+        let clone = email_client.clone();
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let _ = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_eq!(clone.circuit_state(), CircuitState::Open);
+    }
+
+    #[tokio::test]
+    async fn send_email_gives_up_when_the_rate_limiter_would_wait_too_long() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri())
+            .with_retry_policy(RetryPolicy {
+                max_attempts: 3,
+                base_delay: Duration::from_millis(1),
+                max_delay: Duration::from_millis(1),
+                retry_timeouts: false,
+            })
+            .with_rate_limiter(RateLimiter::new(1.0, 1, Duration::ZERO));
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let first = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+        let second = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert - the second email was neither sent nor retried
+        assert_ok!(first);
+        assert!(matches!(second, Err(EmailError::RateLimited { .. })));
+    }
+}
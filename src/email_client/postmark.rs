@@ -0,0 +1,238 @@
+use super::{
+    BatchFailure, BatchOutcome, EmailError, EmailHeader, EmailMessage, EmailSender, SentEmail,
+};
+use chrono::Utc;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Client, Response};
+use secrecy::{ExposeSecret, SecretString};
+use std::time::Duration;
+
+/// Maximum number of messages accepted by Postmark's batch endpoint in a single call
+const MAX_BATCH_SIZE: usize = 500;
+
+/// Backend sending emails through Postmark's HTTP API
+pub struct PostmarkEmailSender {
+    http_client: Client,
+    base_url: String,
+    authorization_token: SecretString,
+}
+
+impl PostmarkEmailSender {
+    pub fn new(base_url: String, authorization_token: SecretString, timeout: Duration) -> Self {
+        let http_client = Client::builder().timeout(timeout).build().unwrap();
+
+        Self {
+            http_client,
+            base_url,
+            authorization_token,
+        }
+    }
+
+    /// Send up to `MAX_BATCH_SIZE` messages with a single call to `/email/batch`.
+    /// Postmark answers with one result per message, in the same order.
+    /// Once the request may have reached Postmark (a timeout, an unreadable answer),
+    /// the outcome is unknown rather than failed, as for single emails.
+    async fn send_chunk(
+        &self,
+        messages: &[EmailMessage<'_>],
+    ) -> Result<Vec<BatchResult>, EmailError> {
+        let url = format!("{}/email/batch", self.base_url);
+        let request_body: Vec<_> = messages.iter().map(SendEmailRequest::from).collect();
+        let response = self
+            .http_client
+            .post(&url)
+            .header(
+                "X-Postmark-Server-Token",
+                self.authorization_token.expose_secret(),
+            )
+            .json(&request_body)
+            .send()
+            .await
+            .map_err(request_error)?;
+        let results: Vec<BatchResult> = check_status(response)?
+            .json()
+            .await
+            .map_err(|e| EmailError::OutcomeUnknown(e.to_string()))?;
+        if results.len() != messages.len() {
+            return Err(EmailError::OutcomeUnknown(format!(
+                "{} results for a batch of {} messages",
+                results.len(),
+                messages.len()
+            )));
+        }
+        Ok(results)
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailSender for PostmarkEmailSender {
+    async fn send(&self, message: &EmailMessage<'_>) -> Result<SentEmail, EmailError> {
+        let url = format!("{}/email", self.base_url);
+        let request_body = SendEmailRequest::from(message);
+
+        let response = self
+            .http_client
+            .post(&url)
+            .header(
+                "X-Postmark-Server-Token",
+                self.authorization_token.expose_secret(),
+            )
+            .json(&request_body)
+            .send()
+            .await
+            .map_err(request_error)?;
+        // The email has been accepted at this point: an unreadable body only costs us its id
+        let message_id = check_status(response)?
+            .json::<SendEmailResponse>()
+            .await
+            .ok()
+            .and_then(|response| response.message_id);
+        Ok(SentEmail { message_id })
+    }
+
+    async fn send_batch(&self, messages: &[EmailMessage<'_>]) -> BatchOutcome {
+        let mut outcome = BatchOutcome::default();
+        for chunk in messages.chunks(MAX_BATCH_SIZE) {
+            match self.send_chunk(chunk).await {
+                Ok(results) => {
+                    for (message, result) in chunk.iter().zip(results) {
+                        // Postmark reports accepted messages with an error code of 0
+                        if result.error_code == 0 {
+                            let sent = SentEmail {
+                                message_id: result.message_id,
+                            };
+                            outcome.sent.push((message.to.clone(), sent));
+                        } else {
+                            outcome.failures.push(BatchFailure {
+                                recipients: vec![message.to.clone()],
+                                error: EmailError::Rejected {
+                                    error_code: result.error_code,
+                                    message: result.message,
+                                },
+                            });
+                        }
+                    }
+                }
+                // Every message of the chunk shares the outcome of the request
+                Err(error) => outcome.failures.push(BatchFailure {
+                    recipients: chunk.iter().map(|message| message.to.clone()).collect(),
+                    error,
+                }),
+            }
+        }
+        outcome
+    }
+}
+
+/// A request which timed out may have reached Postmark: its outcome is unknown
+fn request_error(e: reqwest::Error) -> EmailError {
+    if e.is_timeout() {
+        EmailError::OutcomeUnknown(e.to_string())
+    } else {
+        EmailError::Postmark(e)
+    }
+}
+
+/// Turn a non-2xx response into an error, keeping what the retry policy needs to know
+fn check_status(response: Response) -> Result<Response, EmailError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    Err(EmailError::UnsuccessfulStatus {
+        status: status.as_u16(),
+        retry_after: response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_retry_after),
+    })
+}
+
+/// `Retry-After` is either a number of seconds or an HTTP date
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    // A date in the past means "now"
+    Some(
+        (date.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct SendEmailRequest<'a> {
+    from: &'a str,
+    to: &'a str,
+    subject: &'a str,
+    html_body: &'a str,
+    text_body: &'a str,
+    // Postmark expects a list of {"Name": ..., "Value": ...} objects
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    headers: &'a [EmailHeader<'a>],
+}
+
+impl<'a> From<&'a EmailMessage<'a>> for SendEmailRequest<'a> {
+    fn from(message: &'a EmailMessage<'a>) -> Self {
+        Self {
+            from: message.from.as_ref(),
+            to: message.to.as_ref(),
+            subject: message.subject,
+            html_body: message.html_body,
+            text_body: message.text_body,
+            headers: message.headers,
+        }
+    }
+}
+
+/// Body of a successful `/email` response
+#[derive(serde::Deserialize)]
+struct SendEmailResponse {
+    #[serde(rename = "MessageID")]
+    message_id: Option<String>,
+}
+
+/// Outcome of a single message of a batch
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct BatchResult {
+    error_code: i64,
+    message: String,
+    #[serde(rename = "MessageID")]
+    message_id: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_retry_after;
+    use std::time::Duration;
+
+    #[test]
+    fn retry_after_can_be_a_number_of_seconds() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn retry_after_can_be_an_http_date() {
+        let in_a_minute = (chrono::Utc::now() + chrono::Duration::seconds(60)).to_rfc2822();
+        let delay = parse_retry_after(&in_a_minute).unwrap();
+        assert!(delay > Duration::from_secs(55) && delay <= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn a_past_retry_after_date_means_now() {
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn an_invalid_retry_after_is_ignored() {
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+}
@@ -0,0 +1,266 @@
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Token bucket limiting how many emails we hand over to the provider.
+///
+/// The bucket holds up to `burst` tokens and refills at `messages_per_second`.
+/// Every email takes a token: when the bucket is empty, the caller reserves the next token
+/// and sleeps until it is due, so concurrent callers are served in order.
+/// Callers hold resources while they sleep (e.g. database locks): a caller which would have
+/// to wait longer than `max_wait` is turned away instead, without taking any token.
+/// A single call takes at most `burst` tokens: larger batches are split by the caller.
+/// The bucket lives in memory: it is shared by the tasks of one replica, and every replica
+/// must be given its own share of the provider quota.
+pub struct RateLimiter {
+    messages_per_second: f64,
+    burst: u32,
+    max_wait: Duration,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    // Negative when callers are waiting for tokens which have been reserved in advance
+    tokens: f64,
+    last_refill: Instant,
+    messages: u64,
+    throttled: u64,
+    rejected: u64,
+    wait: Duration,
+}
+
+/// Why the rate limiter turned a caller away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitError {
+    /// The caller would have waited longer than `max_wait`: its messages can go out
+    /// once `retry_after` has elapsed
+    Exhausted { retry_after: Duration },
+    /// More messages than the bucket can ever hold: waiting would not help
+    ExceedsBurst { requested: u32, burst: u32 },
+}
+
+/// Counters of a rate limiter, since the start of the application
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimiterMetrics {
+    /// Messages let through
+    pub messages: u64,
+    /// Messages which had to wait for a token
+    pub throttled: u64,
+    /// Messages turned away because their wait would have exceeded `max_wait`
+    pub rejected: u64,
+    /// Total time spent waiting for tokens
+    pub wait: Duration,
+    /// Tokens left in the bucket
+    pub available_tokens: f64,
+}
+
+impl RateLimiter {
+    pub fn new(messages_per_second: f64, burst: u32, max_wait: Duration) -> Self {
+        assert!(
+            messages_per_second > 0.0,
+            "The email rate limit must be positive"
+        );
+        assert!(burst > 0, "The email burst limit must be positive");
+        Self {
+            messages_per_second,
+            burst,
+            max_wait,
+            inner: Mutex::new(Inner {
+                tokens: f64::from(burst),
+                last_refill: Instant::now(),
+                messages: 0,
+                throttled: 0,
+                rejected: 0,
+                wait: Duration::ZERO,
+            }),
+        }
+    }
+
+    /// Most messages a single `acquire` call can ask for
+    pub fn burst(&self) -> u32 {
+        self.burst
+    }
+
+    pub fn messages_per_second(&self) -> f64 {
+        self.messages_per_second
+    }
+
+    /// Wait until `n` messages can be sent.
+    /// Fails right away when `n` exceeds the burst, or when the caller would have to wait
+    /// longer than `max_wait`.
+    pub async fn acquire(&self, n: u32) -> Result<(), RateLimitError> {
+        if n > self.burst {
+            return Err(RateLimitError::ExceedsBurst {
+                requested: n,
+                burst: self.burst,
+            });
+        }
+        let wait = self
+            .reserve(n)
+            .map_err(|retry_after| RateLimitError::Exhausted { retry_after })?;
+        if !wait.is_zero() {
+            tracing::debug!(
+                wait_ms = wait.as_millis() as u64,
+                "Throttling outgoing emails"
+            );
+            tokio::time::sleep(wait).await;
+        }
+        Ok(())
+    }
+
+    /// Take `permits` tokens, going into debt if needed, and return how long the caller
+    /// has to wait for the debt to be paid back.
+    /// Nothing is taken if that wait would exceed `max_wait`: the debt stays bounded.
+    fn reserve(&self, permits: u32) -> Result<Duration, Duration> {
+        let mut inner = self.inner.lock().unwrap();
+        self.refill(&mut inner);
+        let tokens = inner.tokens - f64::from(permits);
+        let wait = if tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-tokens / self.messages_per_second)
+        };
+        if wait > self.max_wait {
+            inner.rejected += u64::from(permits);
+            return Err(wait);
+        }
+        inner.tokens = tokens;
+        inner.messages += u64::from(permits);
+        if !wait.is_zero() {
+            inner.throttled += u64::from(permits);
+            inner.wait += wait;
+        }
+        Ok(wait)
+    }
+
+    fn refill(&self, inner: &mut Inner) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(inner.last_refill).as_secs_f64();
+        inner.tokens =
+            (inner.tokens + elapsed * self.messages_per_second).min(f64::from(self.burst));
+        inner.last_refill = now;
+    }
+
+    pub fn metrics(&self) -> RateLimiterMetrics {
+        let mut inner = self.inner.lock().unwrap();
+        self.refill(&mut inner);
+        RateLimiterMetrics {
+            messages: inner.messages,
+            throttled: inner.throttled,
+            rejected: inner.rejected,
+            wait: inner.wait,
+            available_tokens: inner.tokens.max(0.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RateLimitError, RateLimiter};
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    const MAX_WAIT: Duration = Duration::from_secs(60);
+
+    #[tokio::test(start_paused = true)]
+    async fn a_full_bucket_lets_a_burst_through_without_waiting() {
+        let limiter = RateLimiter::new(1.0, 5, MAX_WAIT);
+        let start = Instant::now();
+
+        limiter.acquire(5).await.unwrap();
+
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        let metrics = limiter.metrics();
+        assert_eq!(metrics.messages, 5);
+        assert_eq!(metrics.throttled, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn messages_beyond_the_burst_wait_for_the_bucket_to_refill() {
+        let limiter = RateLimiter::new(10.0, 2, MAX_WAIT);
+        let start = Instant::now();
+
+        // 2 messages from the bucket, then 3 more at 10 per second
+        for _ in 0..5 {
+            limiter.acquire(1).await.unwrap();
+        }
+
+        assert_eq!(start.elapsed(), Duration::from_millis(300));
+        let metrics = limiter.metrics();
+        assert_eq!(metrics.messages, 5);
+        assert_eq!(metrics.throttled, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_callers_share_the_same_bucket() {
+        let limiter = std::sync::Arc::new(RateLimiter::new(2.0, 1, MAX_WAIT));
+        let start = Instant::now();
+
+        let tasks: Vec<_> = (0..3)
+            .map(|_| {
+                let limiter = limiter.clone();
+                tokio::spawn(async move { limiter.acquire(1).await.unwrap() })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        // One message right away, then one every 500ms
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+        assert_eq!(limiter.metrics().wait, Duration::from_millis(1500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn more_messages_than_the_burst_are_refused_right_away() {
+        let limiter = RateLimiter::new(4.0, 4, MAX_WAIT);
+        let start = Instant::now();
+
+        let outcome = limiter.acquire(10).await;
+
+        // The bucket never holds more than 4 tokens: waiting would not help
+        assert_eq!(
+            outcome,
+            Err(RateLimitError::ExceedsBurst {
+                requested: 10,
+                burst: 4
+            })
+        );
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        assert_eq!(limiter.metrics().available_tokens, 4.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn callers_are_turned_away_rather_than_waiting_too_long() {
+        let limiter = RateLimiter::new(1.0, 2, Duration::from_secs(1));
+        limiter.acquire(2).await.unwrap();
+        let start = Instant::now();
+
+        // The bucket is empty: 2 more messages would take 2 seconds
+        let outcome = limiter.acquire(2).await;
+
+        assert_eq!(
+            outcome,
+            Err(RateLimitError::Exhausted {
+                retry_after: Duration::from_secs(2)
+            })
+        );
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        let metrics = limiter.metrics();
+        assert_eq!(metrics.messages, 2);
+        assert_eq!(metrics.rejected, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_turned_away_caller_does_not_take_any_token() {
+        let limiter = RateLimiter::new(1.0, 5, Duration::from_secs(1));
+        limiter.acquire(5).await.unwrap();
+        limiter.acquire(5).await.unwrap_err();
+        let start = Instant::now();
+
+        limiter.acquire(1).await.unwrap();
+
+        // Only waiting for its own token
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+}
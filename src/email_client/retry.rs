@@ -0,0 +1,154 @@
+use super::EmailError;
+use rand::Rng;
+use std::time::Duration;
+
+/// How `EmailClient` retries the emails that could not be sent.
+///
+/// Only transient failures are retried (connection errors, `429 Too Many Requests`, `5xx`).
+/// Unknown outcomes such as timeouts may have delivered the email already: they are only
+/// retried with `retry_timeouts`. Retries use an exponential backoff and full jitter:
+/// the n-th retry waits a random delay between zero and `base_delay * 2^(n-1)`,
+/// capped at `max_delay`.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Retry timeouts (and other unknown outcomes), at the risk of a duplicate email
+    pub retry_timeouts: bool,
+}
+
+impl RetryPolicy {
+    /// A single attempt, no retries
+    pub fn no_retry() -> Self {
+        Self {
+            max_attempts: 1,
+            base_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            retry_timeouts: false,
+        }
+    }
+
+    /// How long to wait before the next attempt, or `None` if we should give up.
+    ///
+    /// A `Retry-After` sent back by the provider takes precedence over the backoff. If it
+    /// asks us to wait longer than `max_delay`, we give up instead of hammering the provider.
+    pub fn delay_before_retry(&self, attempt: u32, error: &EmailError) -> Option<Duration> {
+        if attempt >= self.max_attempts || !self.is_retryable(error) {
+            return None;
+        }
+        match error.retry_after() {
+            Some(retry_after) if retry_after > self.max_delay => None,
+            Some(retry_after) => Some(retry_after),
+            None => Some(self.backoff(attempt)),
+        }
+    }
+
+    /// Whether the error is worth another attempt under this policy
+    fn is_retryable(&self, error: &EmailError) -> bool {
+        error.is_retryable()
+            || (self.retry_timeouts && matches!(error, EmailError::OutcomeUnknown(_)))
+    }
+
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .base_delay
+            .saturating_mul(2_u32.saturating_pow(attempt.saturating_sub(1)));
+        let ceiling = exponential.min(self.max_delay);
+        ceiling.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RetryPolicy;
+    use crate::email_client::EmailError;
+    use std::time::Duration;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(300),
+            retry_timeouts: false,
+        }
+    }
+
+    fn server_error() -> EmailError {
+        EmailError::UnsuccessfulStatus {
+            status: 503,
+            retry_after: None,
+        }
+    }
+
+    #[test]
+    fn backoff_grows_exponentially_up_to_the_maximum_delay() {
+        let policy = policy();
+        for _ in 0..100 {
+            assert!(
+                policy.delay_before_retry(1, &server_error()).unwrap()
+                    <= Duration::from_millis(100)
+            );
+            assert!(
+                policy.delay_before_retry(2, &server_error()).unwrap()
+                    <= Duration::from_millis(200)
+            );
+            assert!(
+                policy.delay_before_retry(3, &server_error()).unwrap()
+                    <= Duration::from_millis(300)
+            );
+        }
+    }
+
+    #[test]
+    fn gives_up_after_the_maximum_number_of_attempts() {
+        assert_eq!(policy().delay_before_retry(4, &server_error()), None);
+    }
+
+    #[test]
+    fn client_errors_are_not_retried() {
+        let error = EmailError::UnsuccessfulStatus {
+            status: 422,
+            retry_after: None,
+        };
+        assert_eq!(policy().delay_before_retry(1, &error), None);
+    }
+
+    #[test]
+    fn retry_after_takes_precedence_over_the_backoff() {
+        let error = EmailError::UnsuccessfulStatus {
+            status: 429,
+            retry_after: Some(Duration::from_millis(250)),
+        };
+        assert_eq!(
+            policy().delay_before_retry(1, &error),
+            Some(Duration::from_millis(250))
+        );
+    }
+
+    #[test]
+    fn a_retry_after_longer_than_the_maximum_delay_is_not_waited_for() {
+        let error = EmailError::UnsuccessfulStatus {
+            status: 429,
+            retry_after: Some(Duration::from_secs(60)),
+        };
+        assert_eq!(policy().delay_before_retry(1, &error), None);
+    }
+
+    #[test]
+    fn timeouts_are_not_retried_by_default() {
+        let error = EmailError::OutcomeUnknown("operation timed out".into());
+        assert_eq!(policy().delay_before_retry(1, &error), None);
+    }
+
+    #[test]
+    fn timeouts_are_retried_when_the_policy_allows_it() {
+        let policy = RetryPolicy {
+            retry_timeouts: true,
+            ..policy()
+        };
+        let error = EmailError::OutcomeUnknown("operation timed out".into());
+        assert!(policy.delay_before_retry(1, &error).is_some());
+    }
+}
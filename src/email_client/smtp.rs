@@ -0,0 +1,170 @@
+use super::{mime_message, sent_mime_message, EmailError, EmailMessage, EmailSender, SentEmail};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+use secrecy::{ExposeSecret, SecretString};
+use std::time::Duration;
+
+/// Backend submitting emails to an SMTP server
+pub struct SmtpEmailSender {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpEmailSender {
+    /// With `starttls`, the connection is upgraded with STARTTLS before anything is sent,
+    /// and the submission fails if the server does not support it.
+    /// Without it, everything goes in clear text: only meant for local SMTP sinks.
+    pub fn new(
+        host: &str,
+        port: u16,
+        credentials: Option<(String, SecretString)>,
+        starttls: bool,
+        timeout: Duration,
+    ) -> Result<Self, EmailError> {
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+            .port(port)
+            .timeout(Some(timeout));
+        if starttls {
+            builder = builder.tls(Tls::Required(TlsParameters::new(host.into())?));
+        }
+        if let Some((username, password)) = credentials {
+            builder = builder.credentials(Credentials::new(
+                username,
+                password.expose_secret().to_owned(),
+            ));
+        }
+        Ok(Self {
+            transport: builder.build(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailSender for SmtpEmailSender {
+    async fn send(&self, message: &EmailMessage<'_>) -> Result<SentEmail, EmailError> {
+        let message = mime_message(message)?;
+        let sent = sent_mime_message(&message);
+        // The server may have queued the message before the connection timed out
+        self.transport.send(message).await.map_err(|e| {
+            if e.is_timeout() {
+                EmailError::OutcomeUnknown(e.to_string())
+            } else {
+                EmailError::Smtp(e)
+            }
+        })?;
+        Ok(sent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SmtpEmailSender;
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{EmailMessage, EmailSender};
+    use claim::{assert_err, assert_ok};
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    /// Minimal SMTP sink: accepts a single message and returns what it received after `DATA`.
+    /// It does not advertise STARTTLS.
+    async fn smtp_sink() -> (u16, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            writer.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+            let mut data = String::new();
+            let mut in_data = false;
+            while let Ok(Some(line)) = lines.next_line().await {
+                if in_data {
+                    if line == "." {
+                        in_data = false;
+                        writer.write_all(b"250 OK\r\n").await.unwrap();
+                    } else {
+                        data.push_str(&line);
+                        data.push('\n');
+                    }
+                    continue;
+                }
+                let command = line.to_uppercase();
+                let reply: &[u8] = if command.starts_with("EHLO") {
+                    b"250-localhost\r\n250 8BITMIME\r\n"
+                } else if command.starts_with("DATA") {
+                    in_data = true;
+                    b"354 End data with <CR><LF>.<CR><LF>\r\n"
+                } else if command.starts_with("QUIT") {
+                    writer.write_all(b"221 Bye\r\n").await.unwrap();
+                    break;
+                } else {
+                    b"250 OK\r\n"
+                };
+                writer.write_all(reply).await.unwrap();
+            }
+            data
+        });
+        (port, handle)
+    }
+
+    fn addresses() -> (SubscriberEmail, SubscriberEmail) {
+        (
+            SubscriberEmail::parse("newsletter@example.com".into()).unwrap(),
+            SubscriberEmail::parse("ursula@example.com".into()).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn send_submits_the_message_to_the_smtp_server() {
+        // Arrange
+        let (port, sink) = smtp_sink().await;
+        let sender =
+            SmtpEmailSender::new("127.0.0.1", port, None, false, Duration::from_secs(5)).unwrap();
+        let (from, to) = addresses();
+
+        // Act
+        let outcome = sender
+            .send(&EmailMessage {
+                from: &from,
+                to: &to,
+                subject: "Issue #1",
+                html_body: "<p>Hello</p>",
+                text_body: "Hello",
+                headers: &[],
+            })
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+        let data = sink.await.unwrap();
+        assert!(data.contains("From: newsletter@example.com"));
+        assert!(data.contains("To: ursula@example.com"));
+        assert!(data.contains("Subject: Issue #1"));
+    }
+
+    #[tokio::test]
+    async fn send_fails_if_the_server_does_not_support_starttls() {
+        // Arrange
+        let (port, _sink) = smtp_sink().await;
+        let sender =
+            SmtpEmailSender::new("127.0.0.1", port, None, true, Duration::from_secs(5)).unwrap();
+        let (from, to) = addresses();
+
+        // Act
+        let outcome = sender
+            .send(&EmailMessage {
+                from: &from,
+                to: &to,
+                subject: "Issue #1",
+                html_body: "<p>Hello</p>",
+                text_body: "Hello",
+                headers: &[],
+            })
+            .await;
+
+        // Assert
+        assert_err!(outcome);
+    }
+}
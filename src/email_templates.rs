@@ -0,0 +1,273 @@
+//! Templates of the emails we send, rendered with MiniJinja.
+//!
+//! Templates are loaded from the `templates/` directory (see `ApplicationSettings`), so that
+//! they can be tweaked without a rebuild. A template missing from the directory falls back
+//! to the default version compiled into the binary.
+//! Variables are HTML-escaped in `.html` templates, and left untouched in `.txt` ones.
+use minijinja::{context, Environment, Value};
+use std::path::PathBuf;
+
+/// Default templates, compiled in
+const DEFAULT_TEMPLATES: &[(&str, &str)] = &[
+    (
+        "confirmation.html",
+        include_str!("../templates/confirmation.html"),
+    ),
+    (
+        "confirmation.txt",
+        include_str!("../templates/confirmation.txt"),
+    ),
+    (
+        "issue_layout.html",
+        include_str!("../templates/issue_layout.html"),
+    ),
+    (
+        "issue_layout.txt",
+        include_str!("../templates/issue_layout.txt"),
+    ),
+    (
+        "unsubscribe_footer.html",
+        include_str!("../templates/unsubscribe_footer.html"),
+    ),
+    (
+        "unsubscribe_footer.txt",
+        include_str!("../templates/unsubscribe_footer.txt"),
+    ),
+];
+
+/// HTML and plain text versions of the same content
+#[derive(Debug)]
+pub struct RenderedEmail {
+    pub html: String,
+    pub text: String,
+}
+
+impl RenderedEmail {
+    /// Append `footer` to both versions.
+    /// In a full HTML document (e.g. an issue wrapped in the layout), the footer goes at the
+    /// end of the `<body>`, not after `</html>`.
+    pub fn with_footer(self, footer: &RenderedEmail) -> RenderedEmail {
+        // ASCII lowercasing keeps byte offsets unchanged
+        let html = match self.html.to_ascii_lowercase().rfind("</body>") {
+            Some(end_of_body) => format!(
+                "{}{}{}",
+                &self.html[..end_of_body],
+                footer.html,
+                &self.html[end_of_body..]
+            ),
+            None => format!("{}{}", self.html, footer.html),
+        };
+        RenderedEmail {
+            html,
+            text: format!("{}\n\n{}", self.text, footer.text),
+        }
+    }
+}
+
+pub struct EmailTemplates {
+    environment: Environment<'static>,
+}
+
+impl EmailTemplates {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        let directory = directory.into();
+        let mut environment = Environment::new();
+        environment.set_loader(
+            move |name| match std::fs::read_to_string(directory.join(name)) {
+                Ok(template) => Ok(Some(template)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    let default = DEFAULT_TEMPLATES
+                        .iter()
+                        .find(|(default_name, _)| *default_name == name)
+                        .map(|(_, template)| template.to_string());
+                    Ok(default)
+                }
+                Err(e) => Err(minijinja::Error::new(
+                    minijinja::ErrorKind::InvalidOperation,
+                    format!("Failed to read the {} template", name),
+                )
+                .with_source(e)),
+            },
+        );
+        Self { environment }
+    }
+
+    /// Render `{name}.html` and `{name}.txt` with the same variables
+    pub fn render(&self, name: &str, context: Value) -> Result<RenderedEmail, minijinja::Error> {
+        let html = self
+            .environment
+            .get_template(&format!("{}.html", name))?
+            .render(&context)?;
+        let text = self
+            .environment
+            .get_template(&format!("{}.txt", name))?
+            .render(&context)?;
+        Ok(RenderedEmail { html, text })
+    }
+
+    pub fn confirmation_email(
+        &self,
+        subscriber_name: &str,
+        confirmation_link: &str,
+    ) -> Result<RenderedEmail, minijinja::Error> {
+        self.render(
+            "confirmation",
+            context! {
+                name => subscriber_name,
+                // We build the links ourselves: they are safe, escaping would only mangle them
+                confirmation_link => Value::from_safe_string(confirmation_link.to_owned()),
+            },
+        )
+    }
+
+    /// Wrap the body of a newsletter issue in the layout shared by every issue.
+    /// `html_content` is inserted as is: it must have been sanitized beforehand.
+    pub fn issue_layout(
+        &self,
+        title: &str,
+        html_content: &str,
+        text_content: &str,
+    ) -> Result<RenderedEmail, minijinja::Error> {
+        let html = self
+            .environment
+            .get_template("issue_layout.html")?
+            .render(context! {
+                title,
+                content => Value::from_safe_string(html_content.to_owned()),
+            })?;
+        let text = self
+            .environment
+            .get_template("issue_layout.txt")?
+            .render(context! {
+                title,
+                content => text_content,
+            })?;
+        Ok(RenderedEmail { html, text })
+    }
+
+    pub fn unsubscribe_footer(
+        &self,
+        unsubscribe_link: &str,
+    ) -> Result<RenderedEmail, minijinja::Error> {
+        self.render(
+            "unsubscribe_footer",
+            context! {
+                unsubscribe_link => Value::from_safe_string(unsubscribe_link.to_owned()),
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EmailTemplates, RenderedEmail};
+    use uuid::Uuid;
+
+    fn missing_directory() -> std::path::PathBuf {
+        std::env::temp_dir().join(Uuid::new_v4().to_string())
+    }
+
+    #[test]
+    fn the_compiled_in_defaults_are_used_when_the_directory_is_missing() {
+        let templates = EmailTemplates::new(missing_directory());
+
+        let email = templates
+            .confirmation_email("Ursula", "https://example.com/confirm?token=abc")
+            .unwrap();
+
+        assert!(email.html.contains("Ursula"));
+        assert!(email
+            .html
+            .contains(r#"href="https://example.com/confirm?token=abc""#));
+        assert!(email
+            .text
+            .contains("Visit https://example.com/confirm?token=abc"));
+    }
+
+    #[test]
+    fn variables_are_escaped_in_html_templates_only() {
+        let templates = EmailTemplates::new(missing_directory());
+
+        let email = templates
+            .confirmation_email("<b>Ursula</b> & co", "https://example.com/confirm")
+            .unwrap();
+
+        assert!(email
+            .html
+            .contains("&lt;b&gt;Ursula&lt;&#x2f;b&gt; &amp; co"));
+        assert!(email.text.contains("<b>Ursula</b> & co"));
+    }
+
+    #[test]
+    fn the_issue_layout_escapes_the_title_but_not_the_content() {
+        let templates = EmailTemplates::new(missing_directory());
+
+        let issue = templates
+            .issue_layout("Cats & dogs", "<p>Hello</p>", "Hello")
+            .unwrap();
+
+        assert!(issue.html.contains("<h1>Cats &amp; dogs</h1>"));
+        assert!(issue.html.contains("<p>Hello</p>"));
+        assert!(issue.text.starts_with("Cats & dogs\n\nHello"));
+    }
+
+    #[test]
+    fn the_footer_goes_inside_the_body_of_html_documents() {
+        let templates = EmailTemplates::new(missing_directory());
+        let issue = templates
+            .issue_layout("Title", "<p>Hello</p>", "Hello")
+            .unwrap();
+        let footer = templates
+            .unsubscribe_footer("https://example.com/unsubscribe")
+            .unwrap();
+
+        let email = issue.with_footer(&footer);
+
+        let footer_start = email.html.find(footer.html.as_str()).unwrap();
+        assert!(footer_start > email.html.find("<p>Hello</p>").unwrap());
+        assert!(footer_start < email.html.find("</body>").unwrap());
+        assert!(email.html.trim_end().ends_with("</html>"));
+        assert!(email.text.ends_with(&footer.text));
+    }
+
+    #[test]
+    fn the_footer_is_appended_to_html_fragments() {
+        let fragment = RenderedEmail {
+            html: "<p>Hello</p>".into(),
+            text: "Hello".into(),
+        };
+        let footer = RenderedEmail {
+            html: "<p>Footer</p>".into(),
+            text: "Footer".into(),
+        };
+
+        let email = fragment.with_footer(&footer);
+
+        assert_eq!(email.html, "<p>Hello</p><p>Footer</p>");
+        assert_eq!(email.text, "Hello\n\nFooter");
+    }
+
+    #[test]
+    fn templates_in_the_directory_override_the_defaults() {
+        let directory = missing_directory();
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(
+            directory.join("confirmation.txt"),
+            "Hi {{ name }}, confirm at {{ confirmation_link }}",
+        )
+        .unwrap();
+        let templates = EmailTemplates::new(&directory);
+
+        let email = templates
+            .confirmation_email("Ursula", "https://example.com/confirm")
+            .unwrap();
+
+        assert_eq!(
+            email.text,
+            "Hi Ursula, confirm at https://example.com/confirm"
+        );
+        // The HTML version is not in the directory: the default is used
+        assert!(email.html.contains("Welcome to our newsletter, Ursula!"));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,90 @@
+use actix_web::HttpRequest;
+use uuid::Uuid;
+
+/// Name of the request header carrying the client-generated idempotency key
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+#[derive(Debug)]
+pub struct IdempotencyKey(String);
+
+impl TryFrom<String> for IdempotencyKey {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        if s.is_empty() {
+            return Err("The idempotency key cannot be empty.".into());
+        }
+        // Keys are stored in the database: put an upper bound on their size
+        let max_length = 50;
+        if s.len() >= max_length {
+            return Err(format!(
+                "The idempotency key must be shorter than {} characters.",
+                max_length
+            ));
+        }
+        Ok(Self(s))
+    }
+}
+
+impl AsRef<str> for IdempotencyKey {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+/// What an idempotency key is unique to: the endpoint and, for authenticated requests,
+/// the caller. The same key sent to another endpoint or by another operator is another request.
+#[derive(Debug)]
+pub struct IdempotencyScope(String);
+
+impl IdempotencyScope {
+    pub fn new(route: &str, user_id: Option<Uuid>) -> Self {
+        match user_id {
+            Some(user_id) => Self(format!("{} {}", route, user_id)),
+            None => Self(route.to_owned()),
+        }
+    }
+}
+
+impl AsRef<str> for IdempotencyScope {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Extract the (optional) idempotency key from the request headers.
+///
+/// Returns `Ok(None)` when the client did not send the header,
+/// and an error when the header is present but is not a valid key.
+pub fn get_idempotency_key(request: &HttpRequest) -> Result<Option<IdempotencyKey>, String> {
+    match request.headers().get(IDEMPOTENCY_KEY_HEADER) {
+        None => Ok(None),
+        Some(value) => {
+            let value = value
+                .to_str()
+                .map_err(|_| "The idempotency key must be a valid ASCII string.".to_string())?;
+            IdempotencyKey::try_from(value.to_owned()).map(Some)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IdempotencyKey;
+    use claim::{assert_err, assert_ok};
+
+    #[test]
+    fn empty_key_is_rejected() {
+        assert_err!(IdempotencyKey::try_from("".to_string()));
+    }
+
+    #[test]
+    fn a_50_characters_long_key_is_rejected() {
+        assert_err!(IdempotencyKey::try_from("a".repeat(50)));
+    }
+
+    #[test]
+    fn a_uuid_is_a_valid_key() {
+        assert_ok!(IdempotencyKey::try_from(uuid::Uuid::new_v4().to_string()));
+    }
+}
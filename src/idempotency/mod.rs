@@ -0,0 +1,5 @@
+mod key;
+mod persistence;
+
+pub use key::{get_idempotency_key, IdempotencyKey, IdempotencyScope};
+pub use persistence::{save_response, try_processing, NextAction};
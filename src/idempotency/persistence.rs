@@ -0,0 +1,134 @@
+use super::{IdempotencyKey, IdempotencyScope};
+use actix_web::body::to_bytes;
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+
+// Rust mirror of the `header_pair` composite type defined in the migrations.
+// The derive also teaches sqlx how to bind `Vec<HeaderPairRecord>` (as `header_pair[]`).
+#[derive(Debug, sqlx::Type)]
+#[sqlx(type_name = "header_pair")]
+struct HeaderPairRecord {
+    name: String,
+    value: Vec<u8>,
+}
+
+pub enum NextAction {
+    /// First time we see this key: process the request inside the returned transaction
+    StartProcessing(Transaction<'static, Postgres>),
+    /// The request has already been processed: send the stored response back
+    ReturnSavedResponse(HttpResponse),
+}
+
+/// Reserve the idempotency key for the current request, within `scope`.
+///
+/// The reservation is an uncommitted row: a concurrent request with the same key blocks
+/// on the primary key constraint until the first one commits (and then replays its response)
+/// or rolls back (and then processes the request itself).
+#[tracing::instrument(name = "Reserve idempotency key", skip(pool))]
+pub async fn try_processing(
+    pool: &PgPool,
+    scope: &IdempotencyScope,
+    idempotency_key: &IdempotencyKey,
+) -> Result<NextAction, anyhow::Error> {
+    let mut transaction = pool.begin().await?;
+    let n_inserted_rows = sqlx::query!(
+        r#"INSERT INTO idempotency (scope, idempotency_key, created_at)
+        VALUES ($1, $2, $3)
+        ON CONFLICT DO NOTHING
+        "#,
+        scope.as_ref(),
+        idempotency_key.as_ref(),
+        Utc::now()
+    )
+    .execute(transaction.as_mut())
+    .await?
+    .rows_affected();
+
+    if n_inserted_rows > 0 {
+        Ok(NextAction::StartProcessing(transaction))
+    } else {
+        let saved_response = get_saved_response(pool, scope, idempotency_key)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("We expected a saved response, we didn't find it"))?;
+        Ok(NextAction::ReturnSavedResponse(saved_response))
+    }
+}
+
+#[tracing::instrument(name = "Get saved response", skip(pool))]
+async fn get_saved_response(
+    pool: &PgPool,
+    scope: &IdempotencyScope,
+    idempotency_key: &IdempotencyKey,
+) -> Result<Option<HttpResponse>, anyhow::Error> {
+    let saved_response = sqlx::query!(
+        r#"SELECT
+            response_status_code as "response_status_code!",
+            response_headers as "response_headers!: Vec<HeaderPairRecord>",
+            response_body as "response_body!"
+        FROM idempotency
+        WHERE scope = $1 AND idempotency_key = $2
+        "#,
+        scope.as_ref(),
+        idempotency_key.as_ref()
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    if let Some(r) = saved_response {
+        let status_code = StatusCode::from_u16(r.response_status_code.try_into()?)?;
+        let mut response = HttpResponse::build(status_code);
+        for HeaderPairRecord { name, value } in r.response_headers {
+            response.append_header((name, value));
+        }
+        Ok(Some(response.body(r.response_body)))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Persist the response and commit the transaction opened by `try_processing`.
+#[tracing::instrument(name = "Save response", skip(transaction, http_response))]
+pub async fn save_response(
+    mut transaction: Transaction<'static, Postgres>,
+    scope: &IdempotencyScope,
+    idempotency_key: &IdempotencyKey,
+    http_response: HttpResponse,
+) -> Result<HttpResponse, anyhow::Error> {
+    let (response_head, body) = http_response.into_parts();
+    // `MessageBody::Error` is not `Send` + `Sync`, therefore it doesn't play nicely with `anyhow`
+    let body = to_bytes(body).await.map_err(|e| anyhow::anyhow!("{}", e))?;
+    let status_code = response_head.status().as_u16() as i16;
+    let headers = {
+        let mut h = Vec::with_capacity(response_head.headers().len());
+        for (name, value) in response_head.headers().iter() {
+            let name = name.as_str().to_owned();
+            let value = value.as_bytes().to_owned();
+            h.push(HeaderPairRecord { name, value });
+        }
+        h
+    };
+
+    sqlx::query_unchecked!(
+        r#"UPDATE idempotency
+        SET
+            response_status_code = $3,
+            response_headers = $4,
+            response_body = $5
+        WHERE scope = $1 AND idempotency_key = $2
+        "#,
+        scope.as_ref(),
+        idempotency_key.as_ref(),
+        status_code,
+        headers,
+        body.as_ref()
+    )
+    .execute(transaction.as_mut())
+    .await?;
+    transaction.commit().await?;
+
+    // We need `.map_into_boxed_body` to go from `HttpResponse<Bytes>` to `HttpResponse<BoxBody>`
+    let http_response = response_head.set_body(body).map_into_boxed_body();
+    Ok(http_response)
+}
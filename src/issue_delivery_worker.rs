@@ -0,0 +1,580 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::domain::SubscriberEmail;
+use crate::email_client::{EmailClient, EmailError, EmailHeader, OutgoingEmail, SentEmail};
+use crate::email_templates::{EmailTemplates, RenderedEmail};
+use crate::merge_tags::MergeTags;
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use tracing::{field::display, Span};
+use uuid::Uuid;
+
+/// How long the worker sleeps when there is nothing to deliver (or the database is unavailable)
+const IDLE_DELAY: Duration = Duration::from_secs(1);
+/// Number of failed attempts after which a delivery task is dropped
+const MAX_RETRIES: i16 = 5;
+/// Upper bound on the backoff between two attempts of the same delivery
+const MAX_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// Most delivery tasks sent with a single batch, all of them locked until the batch is done
+const BATCH_SIZE: i64 = 50;
+
+pub enum ExecutionOutcome {
+    TaskCompleted,
+    EmptyQueue,
+}
+
+/// Background worker draining the `issue_delivery_queue` table.
+///
+/// Each replica of the application runs its own worker: tasks are dequeued with
+/// `FOR UPDATE SKIP LOCKED`, so concurrent workers never pick up the same row.
+/// Tasks are dequeued in batches of the same issue and sent with `EmailClient::send_batch`:
+/// only the recipients whose email failed are retried.
+pub struct IssueDeliveryWorker {
+    pool: PgPool,
+    email_client: EmailClient,
+    email_templates: Arc<EmailTemplates>,
+    base_url: String,
+    retry_timeouts: bool,
+}
+
+impl IssueDeliveryWorker {
+    /// With `retry_timeouts`, a delivery whose outcome is unknown is retried like a failed one
+    /// (and may reach the reader twice) instead of being logged as `unknown`.
+    pub fn new(
+        pool: PgPool,
+        email_client: EmailClient,
+        email_templates: Arc<EmailTemplates>,
+        base_url: String,
+        retry_timeouts: bool,
+    ) -> Self {
+        Self {
+            pool,
+            email_client,
+            email_templates,
+            base_url,
+            retry_timeouts,
+        }
+    }
+
+    /// Keep executing delivery tasks, forever.
+    pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
+        loop {
+            match try_execute_task(
+                &self.pool,
+                &self.email_client,
+                &self.email_templates,
+                &self.base_url,
+                self.retry_timeouts,
+            )
+            .await
+            {
+                Ok(ExecutionOutcome::TaskCompleted) => {}
+                Ok(ExecutionOutcome::EmptyQueue) => tokio::time::sleep(IDLE_DELAY).await,
+                Err(_) => tokio::time::sleep(IDLE_DELAY).await,
+            }
+        }
+    }
+}
+
+/// Deliver a batch of tasks of the same issue.
+#[tracing::instrument(
+    skip_all,
+    fields(
+        newsletter_issue_id=tracing::field::Empty,
+        n_tasks=tracing::field::Empty
+    ),
+    err
+)]
+pub async fn try_execute_task(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    email_templates: &EmailTemplates,
+    base_url: &str,
+    retry_timeouts: bool,
+) -> Result<ExecutionOutcome, sqlx::Error> {
+    let (mut transaction, tasks) = dequeue_tasks(pool).await?;
+    let Some(newsletter_issue_id) = tasks.first().map(|task| task.newsletter_issue_id) else {
+        return Ok(ExecutionOutcome::EmptyQueue);
+    };
+    Span::current()
+        .record("newsletter_issue_id", display(newsletter_issue_id))
+        .record("n_tasks", tasks.len());
+
+    let issue = get_issue(pool, newsletter_issue_id).await?;
+    let mut emails = Vec::with_capacity(tasks.len());
+    for task in &tasks {
+        match personalize_issue(pool, email_templates, base_url, &issue, task).await? {
+            Ok(email) => emails.push(email),
+            Err(outcome) => settle_task(&mut transaction, task, outcome).await?,
+        }
+    }
+
+    let outgoing: Vec<_> = emails.iter().map(PersonalizedIssue::outgoing).collect();
+    let outcome = email_client.send_batch(&outgoing).await;
+    let task_of = |recipient: &SubscriberEmail| {
+        tasks
+            .iter()
+            .find(|task| task.subscriber_email == recipient.as_ref())
+            .expect("The batch only has recipients of dequeued tasks")
+    };
+    for (recipient, sent) in outcome.sent {
+        let outcome = DeliveryOutcome::Sent(sent);
+        settle_task(&mut transaction, task_of(&recipient), outcome).await?;
+    }
+    for failure in outcome.failures {
+        for recipient in &failure.recipients {
+            let task = task_of(recipient);
+            let outcome = failed_delivery(task, &failure.error, retry_timeouts);
+            settle_task(&mut transaction, task, outcome).await?;
+        }
+    }
+
+    mark_issue_as_sent_when_done(&mut transaction, newsletter_issue_id).await?;
+    transaction.commit().await?;
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+/// The issue as one of its recipients gets it
+struct PersonalizedIssue {
+    recipient: SubscriberEmail,
+    title: String,
+    body: RenderedEmail,
+    list_unsubscribe: String,
+}
+
+impl PersonalizedIssue {
+    fn outgoing(&self) -> OutgoingEmail<'_> {
+        OutgoingEmail {
+            recipient: self.recipient.clone(),
+            subject: &self.title,
+            html_content: &self.body.html,
+            text_content: &self.body.text,
+            // RFC 8058: mailbox providers can unsubscribe the reader with a single POST
+            // to the link
+            headers: vec![
+                EmailHeader {
+                    name: "List-Unsubscribe",
+                    value: &self.list_unsubscribe,
+                },
+                EmailHeader {
+                    name: "List-Unsubscribe-Post",
+                    value: "List-Unsubscribe=One-Click",
+                },
+            ],
+        }
+    }
+}
+
+/// Render the issue for the recipient of `task`, or tell why it cannot be sent to them
+async fn personalize_issue(
+    pool: &PgPool,
+    email_templates: &EmailTemplates,
+    base_url: &str,
+    issue: &NewsletterIssue,
+    task: &DeliveryTask,
+) -> Result<Result<PersonalizedIssue, DeliveryOutcome>, sqlx::Error> {
+    // An invalid stored email will never become deliverable: drop the task right away.
+    let recipient = match SubscriberEmail::parse(task.subscriber_email.clone()) {
+        Ok(email) => email,
+        Err(e) => {
+            tracing::error!(
+                subscriber_email = %task.subscriber_email,
+                "Skipping a confirmed subscriber. Their stored contact details are invalid: {}",
+                e
+            );
+            let reason = format!("Invalid email address: {}", e);
+            return Ok(Err(DeliveryOutcome::Skipped(reason)));
+        }
+    };
+
+    // The reader may have unsubscribed after the issue was published
+    let Some(subscriber) = get_confirmed_subscriber(pool, &task.subscriber_email).await? else {
+        tracing::info!(
+            subscriber_email = %task.subscriber_email,
+            "Skipping a subscriber who is no longer confirmed"
+        );
+        let reason = "No longer a confirmed subscriber".into();
+        return Ok(Err(DeliveryOutcome::Skipped(reason)));
+    };
+
+    // Every issue carries a link to leave the newsletter
+    let unsubscribe_link = format!(
+        "{}/subscriptions/unsubscribe?token={}",
+        base_url, subscriber.unsubscribe_token
+    );
+    let footer = match email_templates.unsubscribe_footer(&unsubscribe_link) {
+        Ok(footer) => footer,
+        Err(e) => {
+            // A broken template is a deployment problem: back off and keep the task,
+            // rather than sending an issue without its unsubscribe link.
+            tracing::error!("Failed to render the unsubscribe footer: {:?}", e);
+            let error = format!("Failed to render the unsubscribe footer: {}", e);
+            return Ok(Err(retry_or_give_up(task, error)));
+        }
+    };
+    // Merge tags are rendered for this recipient only: the stored issue is shared by everyone
+    let merge_tags = MergeTags {
+        name: &subscriber.name,
+        unsubscribe_url: &unsubscribe_link,
+    };
+    Ok(Ok(PersonalizedIssue {
+        recipient,
+        title: merge_tags.render_text(&issue.title),
+        body: RenderedEmail {
+            html: merge_tags.render_html(&issue.html_content),
+            text: merge_tags.render_text(&issue.text_content),
+        }
+        .with_footer(&footer),
+        list_unsubscribe: format!("<{}>", unsubscribe_link),
+    }))
+}
+
+/// What to do with a task whose email was not accepted
+fn failed_delivery(
+    task: &DeliveryTask,
+    error: &EmailError,
+    retry_timeouts: bool,
+) -> DeliveryOutcome {
+    match error {
+        // Not an attempt: the email never left, and waiting would keep the tasks locked
+        EmailError::RateLimited { retry_after } => {
+            tracing::info!("Rate limit exhausted, delivering later");
+            DeliveryOutcome::Deferred(*retry_after)
+        }
+        // Not an attempt either: an outage must not use up the retries of every task
+        EmailError::CircuitOpen { retry_after } => {
+            tracing::info!("The email provider is unavailable, delivering after the cooldown");
+            // While a trial request is in flight, do not spin on the same tasks
+            DeliveryOutcome::Deferred((*retry_after).max(IDLE_DELAY))
+        }
+        // The provider may have accepted the email: sending it again could deliver it twice
+        EmailError::OutcomeUnknown(e) if !retry_timeouts => {
+            tracing::error!(
+                subscriber_email = %task.subscriber_email,
+                "The delivery of the issue may or may not have succeeded, not retrying: {}",
+                e
+            );
+            DeliveryOutcome::Unknown(e.clone())
+        }
+        e => {
+            tracing::warn!(
+                subscriber_email = %task.subscriber_email,
+                "Failed to deliver issue to a confirmed subscriber: {:?}",
+                e
+            );
+            retry_or_give_up(task, e.to_string())
+        }
+    }
+}
+
+/// A failed attempt is retried later, until the task runs out of attempts
+fn retry_or_give_up(task: &DeliveryTask, error: String) -> DeliveryOutcome {
+    if task.n_retries + 1 >= MAX_RETRIES {
+        tracing::error!(
+            subscriber_email = %task.subscriber_email,
+            "Giving up on the delivery of the issue after {} attempts",
+            MAX_RETRIES
+        );
+        DeliveryOutcome::Failed(error)
+    } else {
+        DeliveryOutcome::Retry(error)
+    }
+}
+
+struct DeliveryTask {
+    newsletter_issue_id: Uuid,
+    subscriber_email: String,
+    n_retries: i16,
+}
+
+type PgTransaction = Transaction<'static, Postgres>;
+
+// The returned transaction holds the row locks: the tasks stay invisible to other workers
+// until they are either deleted or pushed back in the queue.
+// A batch only has tasks of the same issue, the first one ready to go out.
+#[tracing::instrument(skip_all)]
+async fn dequeue_tasks(pool: &PgPool) -> Result<(PgTransaction, Vec<DeliveryTask>), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let tasks = sqlx::query_as!(
+        DeliveryTask,
+        r#"WITH next_task AS (
+            SELECT newsletter_issue_id
+            FROM issue_delivery_queue
+            WHERE execute_after <= now()
+            FOR UPDATE
+            SKIP LOCKED
+            LIMIT 1
+        )
+        SELECT newsletter_issue_id, subscriber_email, n_retries
+        FROM issue_delivery_queue
+        WHERE newsletter_issue_id = (SELECT newsletter_issue_id FROM next_task)
+            AND execute_after <= now()
+        FOR UPDATE
+        SKIP LOCKED
+        LIMIT $1
+        "#,
+        BATCH_SIZE
+    )
+    .fetch_all(transaction.as_mut())
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok((transaction, tasks))
+}
+
+/// How the worker is done with a recipient, for now or for good
+enum DeliveryOutcome {
+    Sent(SentEmail),
+    /// The provider kept refusing the email: no more attempts
+    Failed(String),
+    /// The email was not sent, and never will be
+    Skipped(String),
+    /// The email may or may not have been accepted: no more attempts
+    Unknown(String),
+    /// The attempt failed: try again after a backoff
+    Retry(String),
+    /// The email was not sent: try again after the delay, without counting an attempt
+    Deferred(Duration),
+}
+
+/// Record the outcome in the `issue_deliveries` log, then remove the task from the queue
+/// or push it back.
+async fn settle_task(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+    outcome: DeliveryOutcome,
+) -> Result<(), sqlx::Error> {
+    match outcome {
+        DeliveryOutcome::Sent(sent) => {
+            record_delivery(transaction, task, "sent", true, None, sent.message_id).await?;
+            delete_task(transaction, task).await
+        }
+        DeliveryOutcome::Failed(error) => {
+            record_delivery(transaction, task, "failed", true, Some(error), None).await?;
+            delete_task(transaction, task).await
+        }
+        DeliveryOutcome::Skipped(reason) => {
+            record_delivery(transaction, task, "skipped", false, Some(reason), None).await?;
+            delete_task(transaction, task).await
+        }
+        DeliveryOutcome::Unknown(error) => {
+            record_delivery(transaction, task, "unknown", true, Some(error), None).await?;
+            delete_task(transaction, task).await
+        }
+        DeliveryOutcome::Retry(error) => {
+            record_delivery(transaction, task, "pending", true, Some(error), None).await?;
+            postpone_task(transaction, task).await
+        }
+        DeliveryOutcome::Deferred(delay) => defer_task(transaction, task, delay).await,
+    }
+}
+
+async fn delete_task(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"DELETE FROM issue_delivery_queue
+        WHERE newsletter_issue_id = $1 AND subscriber_email = $2
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email
+    )
+    .execute(transaction.as_mut())
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+/// Move the issue to `sent` once its last delivery task is gone.
+///
+/// The issue row is locked first: workers deleting the last tasks concurrently take turns,
+/// and the last one to commit sees every other deletion.
+async fn mark_issue_as_sent_when_done(
+    transaction: &mut PgTransaction,
+    newsletter_issue_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"SELECT newsletter_issue_id FROM newsletter_issues
+        WHERE newsletter_issue_id = $1
+        FOR UPDATE
+        "#,
+        newsletter_issue_id
+    )
+    .fetch_one(transaction.as_mut())
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    sqlx::query!(
+        r#"UPDATE newsletter_issues
+        SET status = 'sent'
+        WHERE newsletter_issue_id = $1
+            AND status = 'sending'
+            AND NOT EXISTS (
+                SELECT 1 FROM issue_delivery_queue WHERE newsletter_issue_id = $1
+            )
+        "#,
+        newsletter_issue_id
+    )
+    .execute(transaction.as_mut())
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+/// Push a failed task back in the queue with an exponential backoff (2, 4, 8, ... seconds),
+/// capped at `MAX_DELAY`.
+async fn postpone_task(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+) -> Result<(), sqlx::Error> {
+    let n_retries = task.n_retries + 1;
+    let backoff = 2_u64
+        .checked_pow(n_retries as u32)
+        .unwrap_or(u64::MAX)
+        .min(MAX_DELAY.as_secs());
+    let execute_after = Utc::now() + chrono::Duration::seconds(backoff as i64);
+    sqlx::query!(
+        r#"UPDATE issue_delivery_queue
+        SET n_retries = $3, execute_after = $4
+        WHERE newsletter_issue_id = $1 AND subscriber_email = $2
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        n_retries,
+        execute_after
+    )
+    .execute(transaction.as_mut())
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+/// Push a task back in the queue without counting an attempt against it.
+async fn defer_task(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+    delay: Duration,
+) -> Result<(), sqlx::Error> {
+    let execute_after = Utc::now() + chrono::Duration::milliseconds(delay.as_millis() as i64);
+    sqlx::query!(
+        r#"UPDATE issue_delivery_queue
+        SET execute_after = $3
+        WHERE newsletter_issue_id = $1 AND subscriber_email = $2
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        execute_after
+    )
+    .execute(transaction.as_mut())
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+struct ConfirmedSubscriber {
+    name: String,
+    unsubscribe_token: String,
+}
+
+/// Update the delivery log of the task's recipient.
+/// `attempted` tells whether we actually tried to hand the email over to the provider.
+async fn record_delivery(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+    status: &str,
+    attempted: bool,
+    last_error: Option<String>,
+    provider_message_id: Option<String>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE issue_deliveries
+        SET
+            status = $3,
+            n_attempts = n_attempts + $4,
+            last_error = COALESCE($5, last_error),
+            provider_message_id = COALESCE($6, provider_message_id),
+            updated_at = now()
+        WHERE newsletter_issue_id = $1 AND subscriber_email = $2
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        status,
+        i32::from(attempted),
+        last_error,
+        provider_message_id
+    )
+    .execute(transaction.as_mut())
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_confirmed_subscriber(
+    pool: &PgPool,
+    subscriber_email: &str,
+) -> Result<Option<ConfirmedSubscriber>, sqlx::Error> {
+    let subscriber = sqlx::query_as!(
+        ConfirmedSubscriber,
+        r#"SELECT s.name, t.unsubscribe_token
+        FROM subscriptions s
+        JOIN unsubscribe_tokens t ON t.subscriber_id = s.id
+        WHERE s.email = $1 AND s.status = 'confirmed'
+        "#,
+        subscriber_email
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(subscriber)
+}
+
+struct NewsletterIssue {
+    title: String,
+    text_content: String,
+    html_content: String,
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_issue(pool: &PgPool, issue_id: Uuid) -> Result<NewsletterIssue, sqlx::Error> {
+    let issue = sqlx::query_as!(
+        NewsletterIssue,
+        r#"SELECT title, text_content, html_content
+        FROM newsletter_issues
+        WHERE newsletter_issue_id = $1
+        "#,
+        issue_id
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(issue)
+}
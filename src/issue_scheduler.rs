@@ -0,0 +1,112 @@
+use std::time::Duration;
+
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+/// Background task enqueuing the delivery of scheduled newsletter issues.
+///
+/// Every replica runs its own scheduler: due issues are locked with `FOR UPDATE SKIP LOCKED`
+/// and moved to `sending` in the same transaction, so each issue is enqueued exactly once.
+pub struct IssueScheduler {
+    pool: PgPool,
+    interval: Duration,
+}
+
+impl IssueScheduler {
+    pub fn new(pool: PgPool, interval: Duration) -> Self {
+        Self { pool, interval }
+    }
+
+    /// Look for due issues once per `interval`, forever.
+    pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
+        loop {
+            // Failures are already logged: we simply try again at the next tick
+            let _ = enqueue_due_issues(&self.pool).await;
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+}
+
+/// Enqueue the delivery of every scheduled issue whose send date is in the past.
+/// Returns the number of issues that have been enqueued.
+#[tracing::instrument(name = "Enqueue due newsletter issues", skip(pool), err)]
+pub async fn enqueue_due_issues(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let due_issues = sqlx::query_scalar!(
+        r#"SELECT newsletter_issue_id
+        FROM newsletter_issues
+        WHERE status = 'scheduled' AND scheduled_at <= now()
+        FOR UPDATE
+        SKIP LOCKED
+        "#,
+    )
+    .fetch_all(transaction.as_mut())
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    for newsletter_issue_id in &due_issues {
+        enqueue_delivery_tasks(&mut transaction, *newsletter_issue_id).await?;
+    }
+    transaction.commit().await?;
+    if !due_issues.is_empty() {
+        tracing::info!("Enqueued {} scheduled issue(s)", due_issues.len());
+    }
+    Ok(due_issues.len() as u64)
+}
+
+/// Create one delivery task (and its delivery log entry) per confirmed subscriber,
+/// then move the issue to `sending`.
+/// Without any confirmed subscriber there is nothing for the worker to do:
+/// the issue goes straight to `sent`.
+/// Returns the new status of the issue.
+#[tracing::instrument(name = "Enqueue delivery tasks", skip(transaction))]
+pub async fn enqueue_delivery_tasks(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+) -> Result<&'static str, sqlx::Error> {
+    let n_tasks = sqlx::query!(
+        r#"INSERT INTO issue_delivery_queue (newsletter_issue_id, subscriber_email)
+        SELECT $1, email FROM subscriptions WHERE status = 'confirmed'
+        "#,
+        newsletter_issue_id,
+    )
+    .execute(transaction.as_mut())
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .rows_affected();
+    let status = if n_tasks == 0 { "sent" } else { "sending" };
+    sqlx::query!(
+        r#"INSERT INTO issue_deliveries (newsletter_issue_id, subscriber_email)
+        SELECT newsletter_issue_id, subscriber_email
+        FROM issue_delivery_queue
+        WHERE newsletter_issue_id = $1
+        "#,
+        newsletter_issue_id,
+    )
+    .execute(transaction.as_mut())
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    sqlx::query!(
+        r#"UPDATE newsletter_issues
+        SET status = $2, enqueued_at = now()
+        WHERE newsletter_issue_id = $1
+        "#,
+        newsletter_issue_id,
+        status,
+    )
+    .execute(transaction.as_mut())
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(status)
+}
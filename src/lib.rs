@@ -1,6 +1,18 @@
+pub mod authentication;
 pub mod configuration;
 pub mod domain;
 pub mod email_client;
+pub mod email_templates;
+pub mod idempotency;
+pub mod issue_delivery_worker;
+pub mod issue_scheduler;
+pub mod markdown;
+pub mod merge_tags;
+pub mod problem_details;
 pub mod routes;
+pub mod session_state;
+pub mod session_store;
 pub mod startup;
+pub mod subscription_sweeper;
 pub mod telemetry;
+pub mod utils;
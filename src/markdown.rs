@@ -0,0 +1,209 @@
+//! Markdown authoring of newsletter issues.
+//!
+//! An issue written in Markdown is rendered twice: to HTML, sanitized so that authors
+//! cannot smuggle scripts or styles into our emails, and to a readable plain text alternative.
+//! Merge tags are kept as they are, to be replaced when the issue is delivered.
+use crate::merge_tags::replace_tags;
+use minijinja::HtmlEscape;
+use pulldown_cmark::{html, Event, Options, Parser, Tag, TagEnd};
+use uuid::Uuid;
+
+fn parser(markdown: &str) -> Parser<'_> {
+    Parser::new_ext(
+        markdown,
+        Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES,
+    )
+}
+
+/// Merge tags of a Markdown document, swapped for plain words while it is rendered.
+/// Markdown would otherwise mangle them: percent-encoded in a link target, or breaking
+/// the link altogether when they contain spaces (`[Leave]({{ unsubscribe_url }})`).
+struct MergeTagPlaceholders {
+    prefix: String,
+    merge_tags: Vec<String>,
+}
+
+impl MergeTagPlaceholders {
+    fn set_aside(markdown: &str) -> (Self, String) {
+        // Random, so that no author can write a placeholder by accident
+        let mut placeholders = Self {
+            prefix: format!("mergetag{}", Uuid::new_v4().simple()),
+            merge_tags: Vec::new(),
+        };
+        let markdown = replace_tags(markdown, |merge_tag| {
+            placeholders.merge_tags.push(merge_tag.to_owned());
+            placeholders.placeholder(placeholders.merge_tags.len() - 1)
+        });
+        (placeholders, markdown)
+    }
+
+    fn placeholder(&self, index: usize) -> String {
+        format!("{}x{}x", self.prefix, index)
+    }
+
+    /// Put the merge tags back, as `format` turns them into the rendered document
+    fn restore(&self, rendered: &str, format: impl Fn(&str) -> String) -> String {
+        let mut rendered = rendered.to_owned();
+        for (index, merge_tag) in self.merge_tags.iter().enumerate() {
+            rendered = rendered.replace(&self.placeholder(index), &format(merge_tag));
+        }
+        rendered
+    }
+}
+
+/// Render Markdown to HTML, keeping only safe tags and attributes
+pub fn to_html(markdown: &str) -> String {
+    let (placeholders, markdown) = MergeTagPlaceholders::set_aside(markdown);
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, parser(&markdown));
+    // Escaped: the fallback of a merge tag is inserted as it is when the issue is delivered
+    placeholders.restore(&ammonia::clean(&unsafe_html), |merge_tag| {
+        HtmlEscape(merge_tag).to_string()
+    })
+}
+
+/// Render Markdown to plain text: markup is dropped, link targets are kept in parentheses
+pub fn to_text(markdown: &str) -> String {
+    let (placeholders, markdown) = MergeTagPlaceholders::set_aside(markdown);
+    placeholders.restore(&render_text(&markdown), str::to_owned)
+}
+
+fn render_text(markdown: &str) -> String {
+    let mut text = String::new();
+    // Targets of the links we are in, so that we can print them once the link text is done
+    let mut links = Vec::new();
+    // Current list nesting: `Some(n)` for ordered lists, `None` for bullet lists
+    let mut lists: Vec<Option<u64>> = Vec::new();
+    for event in parser(markdown) {
+        match event {
+            Event::Text(s) | Event::Code(s) => text.push_str(&s),
+            Event::SoftBreak => text.push(' '),
+            Event::HardBreak => text.push('\n'),
+            Event::Rule => text.push_str("----------\n\n"),
+            Event::Start(Tag::Link { dest_url, .. }) => links.push(dest_url),
+            Event::End(TagEnd::Link) => {
+                if let Some(url) = links.pop() {
+                    text.push_str(&format!(" ({})", url));
+                }
+            }
+            Event::Start(Tag::List(first_number)) => {
+                // A nested list starts on its own line, below the text of its parent item
+                if !lists.is_empty() && !text.ends_with('\n') {
+                    text.push('\n');
+                }
+                lists.push(first_number);
+            }
+            Event::End(TagEnd::List(_)) => {
+                lists.pop();
+                if lists.is_empty() {
+                    text.push('\n');
+                }
+            }
+            Event::Start(Tag::Item) => {
+                let indent = "  ".repeat(lists.len().saturating_sub(1));
+                text.push_str(&indent);
+                match lists.last_mut() {
+                    Some(Some(number)) => {
+                        text.push_str(&format!("{}. ", number));
+                        *number += 1;
+                    }
+                    _ => text.push_str("- "),
+                }
+            }
+            Event::End(TagEnd::Item) if !text.ends_with('\n') => text.push('\n'),
+            // Further paragraphs of a list item are indented under its first line
+            Event::Start(Tag::Paragraph) if !lists.is_empty() && text.ends_with('\n') => {
+                text.push_str(&"  ".repeat(lists.len()));
+            }
+            Event::End(TagEnd::Paragraph) if lists.is_empty() => text.push_str("\n\n"),
+            Event::End(TagEnd::Paragraph) => text.push('\n'),
+            Event::End(TagEnd::Heading(_))
+            | Event::End(TagEnd::CodeBlock)
+            | Event::End(TagEnd::BlockQuote(_))
+            | Event::End(TagEnd::Table)
+                if !text.ends_with("\n\n") =>
+            {
+                text.push_str(if text.ends_with('\n') { "\n" } else { "\n\n" });
+            }
+            Event::End(TagEnd::TableCell) => text.push('\t'),
+            Event::End(TagEnd::TableRow) | Event::End(TagEnd::TableHead) => text.push('\n'),
+            _ => {}
+        }
+    }
+    text.trim_end().to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{to_html, to_text};
+
+    #[test]
+    fn markdown_is_rendered_to_html() {
+        let html = to_html("# Issue #1\n\nSome **bold** text and a [link](https://example.com).");
+
+        assert!(html.contains("<h1>Issue #1</h1>"));
+        assert!(html.contains("<strong>bold</strong>"));
+        assert!(html.contains(r#"<a href="https://example.com""#));
+    }
+
+    #[test]
+    fn scripts_and_event_handlers_are_removed_from_the_html() {
+        let html = to_html(
+            "Hello <script>alert('pwned')</script><img src=\"x.png\" onerror=\"alert(1)\">\n\n[click](javascript:alert(1))",
+        );
+
+        assert!(!html.contains("<script"));
+        assert!(!html.contains("onerror"));
+        assert!(!html.contains("javascript:"));
+    }
+
+    #[test]
+    fn markdown_is_rendered_to_readable_plain_text() {
+        let text = to_text(
+            "# Issue #1\n\nSome **bold** text and a [link](https://example.com).\n\n- first\n- second\n\n1. one\n2. two",
+        );
+
+        assert_eq!(
+            text,
+            "Issue #1\n\nSome bold text and a link (https://example.com).\n\n- first\n- second\n\n1. one\n2. two"
+        );
+    }
+
+    #[test]
+    fn nested_lists_are_rendered_on_their_own_lines() {
+        let text = to_text("- first\n  - nested\n- second");
+
+        assert_eq!(text, "- first\n  - nested\n- second");
+    }
+
+    #[test]
+    fn paragraphs_of_a_list_item_are_kept_apart() {
+        let text = to_text("- para one\n\n  para two\n- next");
+
+        assert_eq!(text, "- para one\n  para two\n- next");
+    }
+
+    #[test]
+    fn merge_tags_are_kept_in_link_targets() {
+        let markdown =
+            "Hi {{ name }}, [leave]({{ unsubscribe_url }}) or [stay]({{unsubscribe_url}})";
+
+        let html = to_html(markdown);
+        let text = to_text(markdown);
+
+        assert!(html.contains("Hi {{ name }}"));
+        assert!(html.contains(r#"<a href="{{ unsubscribe_url }}""#));
+        assert!(html.contains(r#"<a href="{{unsubscribe_url}}""#));
+        assert_eq!(
+            text,
+            "Hi {{ name }}, leave ({{ unsubscribe_url }}) or stay ({{unsubscribe_url}})"
+        );
+    }
+
+    #[test]
+    fn merge_tag_fallbacks_are_escaped_in_the_html() {
+        let html = to_html("Hi {{ name | <script>alert(1)</script> }}");
+
+        assert!(!html.contains("<script"));
+    }
+}
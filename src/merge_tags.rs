@@ -0,0 +1,114 @@
+//! Per-recipient personalization of newsletter issues.
+//!
+//! Authors can use merge tags such as `{{ name }}` or `{{ unsubscribe_url }}` in the title and
+//! content of an issue: they are replaced when the issue is delivered to each subscriber.
+//! A fallback can be given after a pipe, `{{ name | there }}`, and is used whenever the tag
+//! has no value for the recipient. Unknown tags without a fallback are left as they are.
+use minijinja::HtmlEscape;
+
+/// Values of the merge tags for a single recipient
+pub struct MergeTags<'a> {
+    pub name: &'a str,
+    pub unsubscribe_url: &'a str,
+}
+
+impl MergeTags<'_> {
+    fn value(&self, tag: &str) -> Option<Option<&str>> {
+        let value = match tag {
+            "name" => self.name,
+            "unsubscribe_url" => self.unsubscribe_url,
+            _ => return None,
+        };
+        Some(Some(value.trim()).filter(|v| !v.is_empty()))
+    }
+
+    /// Replace the merge tags of an HTML document: values are HTML-escaped,
+    /// fallbacks are part of the (already sanitized) document and are inserted as they are.
+    pub fn render_html(&self, content: &str) -> String {
+        self.render(content, |value| HtmlEscape(value).to_string())
+    }
+
+    /// Replace the merge tags of a plain text document
+    pub fn render_text(&self, content: &str) -> String {
+        self.render(content, str::to_owned)
+    }
+
+    fn render(&self, content: &str, format_value: impl Fn(&str) -> String) -> String {
+        replace_tags(content, |merge_tag| {
+            let inner = &merge_tag[2..merge_tag.len() - 2];
+            let (tag, fallback) = match inner.split_once('|') {
+                Some((tag, fallback)) => (tag.trim(), Some(fallback.trim())),
+                None => (inner.trim(), None),
+            };
+            match (self.value(tag), fallback) {
+                (Some(Some(value)), _) => format_value(value),
+                (_, Some(fallback)) => fallback.to_owned(),
+                (Some(None), None) => String::new(),
+                (None, None) => merge_tag.to_owned(),
+            }
+        })
+    }
+}
+
+/// Replace every merge tag of `content`, braces included, with what `replace` returns for it
+pub fn replace_tags(content: &str, mut replace: impl FnMut(&str) -> String) -> String {
+    let mut output = String::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(start) = rest.find("{{") {
+        let Some(length) = rest[start..].find("}}") else {
+            break;
+        };
+        let end = start + length + 2;
+        output.push_str(&rest[..start]);
+        output.push_str(&replace(&rest[start..end]));
+        rest = &rest[end..];
+    }
+    output.push_str(rest);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MergeTags;
+
+    const TAGS: MergeTags = MergeTags {
+        name: "Zasha & <co>",
+        unsubscribe_url: "https://example.com/unsubscribe?token=abc",
+    };
+
+    #[test]
+    fn tags_are_replaced_by_the_recipient_values() {
+        let text = TAGS.render_text("Hi {{ name }}! Leave at {{unsubscribe_url}}");
+
+        assert_eq!(
+            text,
+            "Hi Zasha & <co>! Leave at https://example.com/unsubscribe?token=abc"
+        );
+    }
+
+    #[test]
+    fn values_are_escaped_in_html() {
+        let html = TAGS.render_html("<p>Hi {{ name }}!</p>");
+
+        assert_eq!(html, "<p>Hi Zasha &amp; &lt;co&gt;!</p>");
+    }
+
+    #[test]
+    fn the_fallback_is_used_when_the_tag_has_no_value() {
+        let tags = MergeTags {
+            name: " ",
+            unsubscribe_url: "https://example.com/unsubscribe",
+        };
+
+        assert_eq!(tags.render_text("Hi {{ name | there }}!"), "Hi there!");
+        assert_eq!(tags.render_text("Hi{{ name }}!"), "Hi!");
+        assert_eq!(TAGS.render_text("{{ city | Paris }}"), "Paris");
+    }
+
+    #[test]
+    fn unknown_tags_and_unterminated_braces_are_left_untouched() {
+        let text = TAGS.render_text("{{ city }} and {{ name");
+
+        assert_eq!(text, "{{ city }} and {{ name");
+    }
+}
@@ -0,0 +1,109 @@
+//! RFC 9457 "Problem Details for HTTP APIs".
+//!
+//! API clients asking for JSON (`Accept: application/json`) get errors as
+//! `application/problem+json` documents instead of empty or plain text bodies.
+use crate::routes::SubscribeError;
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, Header, HeaderMap, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+
+pub const PROBLEM_JSON_CONTENT_TYPE: &str = "application/problem+json";
+
+#[derive(Debug, serde::Serialize)]
+pub struct ProblemDetails {
+    /// URI reference identifying the problem type, `about:blank` when the status code says it all
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub title: String,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// Extension member listing the invalid fields of the request body
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+impl ProblemDetails {
+    pub fn from_status(status: StatusCode) -> Self {
+        Self {
+            type_: "about:blank".into(),
+            title: status.canonical_reason().unwrap_or("Unknown error").into(),
+            status: status.as_u16(),
+            detail: None,
+            errors: Vec::new(),
+        }
+    }
+}
+
+/// A single invalid field of the request body
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct FieldError {
+    /// JSON pointer to the field, e.g. `#/email`
+    pub pointer: String,
+    pub detail: String,
+}
+
+impl FieldError {
+    pub fn new(field: &str, detail: String) -> Self {
+        Self {
+            pointer: format!("#/{}", field),
+            detail,
+        }
+    }
+}
+
+/// Middleware turning error responses into problem documents for clients accepting JSON.
+///
+/// Plain form posts keep the regular error responses. The original error stays attached
+/// to the response, so the tracing middleware still logs its whole chain of causes.
+pub async fn problem_json_errors(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let wants_json = accepts_json(&req);
+    let response = next.call(req).await?.map_into_boxed_body();
+    if !wants_json {
+        return Ok(response);
+    }
+    let problem = match response.response().error() {
+        None => return Ok(response),
+        Some(e) => match e.as_error::<SubscribeError>() {
+            Some(e) => e.problem_details(),
+            None => {
+                let status = response.status();
+                let mut problem = ProblemDetails::from_status(status);
+                // Extractor errors (e.g. a missing field) are safe to show,
+                // server errors are not.
+                if status.is_client_error() {
+                    problem.detail = Some(e.to_string());
+                }
+                problem
+            }
+        },
+    };
+    let body =
+        serde_json::to_vec(&problem).expect("A problem document can always be serialized to JSON");
+    Ok(response.map_body(|head, _| {
+        set_problem_json_content_type(&mut head.headers);
+        BoxBody::new(body)
+    }))
+}
+
+fn set_problem_json_content_type(headers: &mut HeaderMap) {
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(PROBLEM_JSON_CONTENT_TYPE),
+    );
+}
+
+fn accepts_json(req: &ServiceRequest) -> bool {
+    match header::Accept::parse(req) {
+        Ok(accept) => accept.iter().any(|item| {
+            let essence = item.item.essence_str();
+            essence == "application/json" || essence == PROBLEM_JSON_CONTENT_TYPE
+        }),
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,9 @@
+mod dashboard;
+mod logout;
+mod newsletters;
+mod password;
+
+pub use dashboard::admin_dashboard;
+pub use logout::log_out;
+pub use newsletters::*;
+pub use password::*;
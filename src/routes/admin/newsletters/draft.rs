@@ -0,0 +1,80 @@
+use super::{not_editable_error, IssueError};
+use crate::email_templates::EmailTemplates;
+use crate::routes::{insert_newsletter_issue, Content};
+use actix_web::{web, HttpResponse};
+use anyhow::Context;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+// Same payload as the publication of an issue, without any send date
+#[derive(serde::Deserialize)]
+pub struct DraftData {
+    title: String,
+    content: Content,
+}
+
+#[derive(serde::Serialize)]
+struct SavedDraft {
+    newsletter_issue_id: Uuid,
+}
+
+/// Save a new issue, without sending nor scheduling it
+#[tracing::instrument(name = "Create a newsletter draft", skip(body, pool, email_templates))]
+pub async fn create_draft(
+    body: web::Json<DraftData>,
+    pool: web::Data<PgPool>,
+    email_templates: web::Data<EmailTemplates>,
+) -> Result<HttpResponse, IssueError> {
+    let content = body
+        .content
+        .render(&body.title, &email_templates)
+        .context("Failed to render the issue layout.")?;
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+    let newsletter_issue_id =
+        insert_newsletter_issue(&mut transaction, &body.title, &content, None)
+            .await
+            .context("Failed to store the newsletter draft.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the SQL transaction to store a newsletter draft.")?;
+    Ok(HttpResponse::Ok().json(SavedDraft {
+        newsletter_issue_id,
+    }))
+}
+
+/// Replace the title and content of a draft or scheduled issue
+#[tracing::instrument(name = "Update a newsletter draft", skip(body, pool, email_templates))]
+pub async fn update_draft(
+    newsletter_issue_id: web::Path<Uuid>,
+    body: web::Json<DraftData>,
+    pool: web::Data<PgPool>,
+    email_templates: web::Data<EmailTemplates>,
+) -> Result<HttpResponse, IssueError> {
+    let newsletter_issue_id = newsletter_issue_id.into_inner();
+    let content = body
+        .content
+        .render(&body.title, &email_templates)
+        .context("Failed to render the issue layout.")?;
+    let updated = sqlx::query!(
+        r#"UPDATE newsletter_issues
+        SET title = $2, text_content = $3, html_content = $4
+        WHERE newsletter_issue_id = $1 AND status IN ('draft', 'scheduled')
+        "#,
+        newsletter_issue_id,
+        body.title,
+        content.text,
+        content.html
+    )
+    .execute(pool.get_ref())
+    .await
+    .context("Failed to update the newsletter draft.")?
+    .rows_affected();
+    if updated == 0 {
+        return Err(not_editable_error(&pool, newsletter_issue_id).await);
+    }
+    Ok(HttpResponse::Ok().finish())
+}
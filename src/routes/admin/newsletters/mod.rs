@@ -0,0 +1,127 @@
+//! Editorial workflow of newsletter issues: draft -> scheduled -> sending -> sent.
+//!
+//! Drafts and scheduled issues can be edited, previewed, test-sent, (re)scheduled or sent.
+//! Once the delivery tasks have been enqueued the issue is frozen.
+mod draft;
+mod preview;
+mod report;
+mod schedule;
+mod test_send;
+
+pub use draft::*;
+pub use preview::*;
+pub use report::*;
+pub use schedule::*;
+pub use test_send::*;
+
+use crate::email_templates::{EmailTemplates, RenderedEmail};
+use crate::merge_tags::MergeTags;
+use crate::utils::error_chain_fmt;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use anyhow::Context;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(thiserror::Error)]
+pub enum IssueError {
+    #[error("There is no newsletter issue with the provided id.")]
+    UnknownIssue,
+    /// The delivery tasks have been created: the issue can no longer be changed
+    #[error("The newsletter issue is already being sent.")]
+    AlreadyEnqueued,
+    #[error("{0}")]
+    ValidationError(String),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for IssueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for IssueError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            IssueError::UnknownIssue => StatusCode::NOT_FOUND,
+            IssueError::AlreadyEnqueued => StatusCode::CONFLICT,
+            IssueError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            IssueError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            IssueError::UnexpectedError(_) => HttpResponse::build(self.status_code()).finish(),
+            _ => HttpResponse::build(self.status_code()).body(self.to_string()),
+        }
+    }
+}
+
+/// Tell apart an unknown issue from one that has left the editable states,
+/// after an update guarded by `status IN ('draft', 'scheduled')` did not match any row.
+async fn not_editable_error(pool: &PgPool, newsletter_issue_id: Uuid) -> IssueError {
+    let exists = sqlx::query_scalar!(
+        r#"SELECT EXISTS (
+            SELECT 1 FROM newsletter_issues WHERE newsletter_issue_id = $1
+        ) as "exists!"
+        "#,
+        newsletter_issue_id
+    )
+    .fetch_one(pool)
+    .await
+    .context("Failed to look up the newsletter issue.");
+    match exists {
+        Ok(true) => IssueError::AlreadyEnqueued,
+        Ok(false) => IssueError::UnknownIssue,
+        Err(e) => IssueError::UnexpectedError(e),
+    }
+}
+
+struct StoredIssue {
+    title: String,
+    text_content: String,
+    html_content: String,
+}
+
+async fn get_issue(pool: &PgPool, newsletter_issue_id: Uuid) -> Result<StoredIssue, IssueError> {
+    sqlx::query_as!(
+        StoredIssue,
+        r#"SELECT title, text_content, html_content
+        FROM newsletter_issues
+        WHERE newsletter_issue_id = $1
+        "#,
+        newsletter_issue_id
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to retrieve the newsletter issue.")?
+    .ok_or(IssueError::UnknownIssue)
+}
+
+/// Render an issue the way a reader would receive it, with the operator standing in
+/// for the reader. The unsubscribe link carries no token: it would not unsubscribe anyone.
+fn render_sample(
+    issue: &StoredIssue,
+    operator_name: &str,
+    email_templates: &EmailTemplates,
+    base_url: &str,
+) -> Result<(String, RenderedEmail), IssueError> {
+    let unsubscribe_url = format!("{}/subscriptions/unsubscribe", base_url);
+    let footer = email_templates
+        .unsubscribe_footer(&unsubscribe_url)
+        .context("Failed to render the unsubscribe footer.")?;
+    let merge_tags = MergeTags {
+        name: operator_name,
+        unsubscribe_url: &unsubscribe_url,
+    };
+    let title = merge_tags.render_text(&issue.title);
+    let content = RenderedEmail {
+        html: merge_tags.render_html(&issue.html_content),
+        text: merge_tags.render_text(&issue.text_content),
+    }
+    .with_footer(&footer);
+    Ok((title, content))
+}
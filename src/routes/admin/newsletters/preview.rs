@@ -0,0 +1,34 @@
+use super::{get_issue, render_sample, IssueError};
+use crate::authentication::UserId;
+use crate::email_templates::EmailTemplates;
+use crate::routes::admin::dashboard::get_username;
+use crate::startup::ApplicationBaseUrl;
+use actix_web::http::header::{ContentType, CONTENT_SECURITY_POLICY};
+use actix_web::{web, HttpResponse};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// HTML version of an issue, as it would reach the inbox of the logged-in operator.
+///
+/// Hand-written HTML issues are not sanitized: the preview is served in a sandbox
+/// (no scripts, unique origin) so that it cannot act with the operator's session.
+#[tracing::instrument(
+    name = "Preview a newsletter issue",
+    skip(pool, email_templates, base_url, user_id),
+    fields(user_id=%*user_id)
+)]
+pub async fn preview_issue(
+    newsletter_issue_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    email_templates: web::Data<EmailTemplates>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, IssueError> {
+    let issue = get_issue(&pool, newsletter_issue_id.into_inner()).await?;
+    let username = get_username(*user_id.into_inner(), &pool).await?;
+    let (_, content) = render_sample(&issue, &username, &email_templates, &base_url.0)?;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .insert_header((CONTENT_SECURITY_POLICY, "sandbox"))
+        .body(content.html))
+}
@@ -0,0 +1,50 @@
+use super::IssueError;
+use actix_web::{web, HttpResponse};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// Status of an issue and of its deliveries
+#[derive(serde::Serialize)]
+pub struct IssueReport {
+    newsletter_issue_id: Uuid,
+    title: String,
+    status: String,
+    scheduled_at: Option<DateTime<Utc>>,
+    enqueued_at: Option<DateTime<Utc>>,
+    sent: i64,
+    failed: i64,
+    pending: i64,
+    skipped: i64,
+    /// Deliveries which may or may not have reached the recipient (e.g. a timeout)
+    unknown: i64,
+}
+
+/// Every issue, most recent first, with its delivery counts
+#[tracing::instrument(name = "Report on newsletter issues", skip(pool))]
+pub async fn issues_report(pool: web::Data<PgPool>) -> Result<HttpResponse, IssueError> {
+    let reports = sqlx::query_as!(
+        IssueReport,
+        r#"SELECT
+            i.newsletter_issue_id,
+            i.title,
+            i.status,
+            i.scheduled_at,
+            i.enqueued_at,
+            COUNT(*) FILTER (WHERE d.status = 'sent') as "sent!",
+            COUNT(*) FILTER (WHERE d.status = 'failed') as "failed!",
+            COUNT(*) FILTER (WHERE d.status = 'pending') as "pending!",
+            COUNT(*) FILTER (WHERE d.status = 'skipped') as "skipped!",
+            COUNT(*) FILTER (WHERE d.status = 'unknown') as "unknown!"
+        FROM newsletter_issues i
+        LEFT JOIN issue_deliveries d ON d.newsletter_issue_id = i.newsletter_issue_id
+        GROUP BY i.newsletter_issue_id
+        ORDER BY i.published_at DESC
+        "#
+    )
+    .fetch_all(pool.get_ref())
+    .await
+    .context("Failed to build the report on newsletter issues.")?;
+    Ok(HttpResponse::Ok().json(reports))
+}
@@ -0,0 +1,109 @@
+use super::{not_editable_error, IssueError};
+use crate::issue_scheduler::enqueue_delivery_tasks;
+use actix_web::{web, HttpResponse};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(serde::Deserialize)]
+pub struct ScheduleData {
+    scheduled_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize)]
+struct ScheduledIssue {
+    newsletter_issue_id: Uuid,
+    scheduled_at: DateTime<Utc>,
+}
+
+/// Set or move the send date of a draft or scheduled issue
+#[tracing::instrument(name = "Reschedule a newsletter issue", skip(body, pool))]
+pub async fn reschedule_issue(
+    newsletter_issue_id: web::Path<Uuid>,
+    body: web::Json<ScheduleData>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, IssueError> {
+    let newsletter_issue_id = newsletter_issue_id.into_inner();
+    update_schedule(&pool, newsletter_issue_id, Some(body.scheduled_at)).await?;
+    Ok(HttpResponse::Ok().json(ScheduledIssue {
+        newsletter_issue_id,
+        scheduled_at: body.scheduled_at,
+    }))
+}
+
+/// Call off the send of a scheduled issue: it goes back to being a draft.
+#[tracing::instrument(name = "Cancel a scheduled newsletter issue", skip(pool))]
+pub async fn cancel_scheduled_issue(
+    newsletter_issue_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, IssueError> {
+    update_schedule(&pool, newsletter_issue_id.into_inner(), None).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Enqueue the delivery of a draft or scheduled issue right away
+#[tracing::instrument(name = "Send a newsletter issue", skip(pool))]
+pub async fn send_issue(
+    newsletter_issue_id: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, IssueError> {
+    let newsletter_issue_id = newsletter_issue_id.into_inner();
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+    // Same lock as the scheduler: the issue is enqueued by one of us only
+    let editable = sqlx::query!(
+        r#"SELECT newsletter_issue_id FROM newsletter_issues
+        WHERE newsletter_issue_id = $1 AND status IN ('draft', 'scheduled')
+        FOR UPDATE
+        "#,
+        newsletter_issue_id
+    )
+    .fetch_optional(transaction.as_mut())
+    .await
+    .context("Failed to lock the newsletter issue.")?;
+    if editable.is_none() {
+        return Err(not_editable_error(&pool, newsletter_issue_id).await);
+    }
+    enqueue_delivery_tasks(&mut transaction, newsletter_issue_id)
+        .await
+        .context("Failed to enqueue the delivery tasks.")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the SQL transaction to send a newsletter issue.")?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+// The update waits for the row lock held by a scheduler enqueuing the issue, then re-checks
+// the status: an issue cannot be rescheduled once its delivery tasks exist.
+async fn update_schedule(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+    scheduled_at: Option<DateTime<Utc>>,
+) -> Result<(), IssueError> {
+    let status = if scheduled_at.is_some() {
+        "scheduled"
+    } else {
+        "draft"
+    };
+    let updated = sqlx::query!(
+        r#"UPDATE newsletter_issues
+        SET scheduled_at = $2, status = $3
+        WHERE newsletter_issue_id = $1 AND status IN ('draft', 'scheduled')
+        "#,
+        newsletter_issue_id,
+        scheduled_at,
+        status
+    )
+    .execute(pool)
+    .await
+    .context("Failed to update the schedule of the newsletter issue.")?
+    .rows_affected();
+    if updated == 0 {
+        return Err(not_editable_error(pool, newsletter_issue_id).await);
+    }
+    Ok(())
+}
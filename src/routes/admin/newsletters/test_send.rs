@@ -0,0 +1,48 @@
+use super::{get_issue, render_sample, IssueError};
+use crate::authentication::UserId;
+use crate::domain::SubscriberEmail;
+use crate::email_client::EmailClient;
+use crate::email_templates::EmailTemplates;
+use crate::routes::admin::dashboard::get_username;
+use crate::startup::ApplicationBaseUrl;
+use actix_web::{web, HttpResponse};
+use anyhow::Context;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(serde::Deserialize)]
+pub struct TestSendData {
+    email: String,
+}
+
+/// Send a single copy of an issue to the given address, whatever its status.
+/// The issue itself is left untouched.
+#[tracing::instrument(
+    name = "Send a test copy of a newsletter issue",
+    skip(body, pool, email_client, email_templates, base_url, user_id),
+    fields(user_id=%*user_id)
+)]
+pub async fn send_test_issue(
+    newsletter_issue_id: web::Path<Uuid>,
+    body: web::Json<TestSendData>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    email_templates: web::Data<EmailTemplates>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, IssueError> {
+    let recipient = SubscriberEmail::parse(body.0.email).map_err(IssueError::ValidationError)?;
+    let issue = get_issue(&pool, newsletter_issue_id.into_inner()).await?;
+    let username = get_username(*user_id.into_inner(), &pool).await?;
+    let (title, content) = render_sample(&issue, &username, &email_templates, &base_url.0)?;
+    email_client
+        .send_email(
+            recipient,
+            &format!("[Test] {}", title),
+            &content.html,
+            &content.text,
+        )
+        .await
+        .context("Failed to send the test copy of the newsletter issue.")?;
+    Ok(HttpResponse::Ok().finish())
+}
@@ -0,0 +1,75 @@
+use crate::authentication::{self, validate_credentials, AuthError, Credentials, UserId};
+use crate::routes::admin::dashboard::get_username;
+use crate::utils::see_other;
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::FlashMessage;
+use secrecy::{ExposeSecret, SecretString};
+use sqlx::PgPool;
+
+/// Bounds on the length of a new password, in characters
+const MIN_PASSWORD_LENGTH: usize = 12;
+const MAX_PASSWORD_LENGTH: usize = 128;
+
+#[derive(serde::Deserialize)]
+pub struct FormData {
+    current_password: SecretString,
+    new_password: SecretString,
+    new_password_check: SecretString,
+}
+
+#[tracing::instrument(name = "Change password", skip(form, pool, user_id), fields(user_id=%*user_id))]
+pub async fn change_password(
+    form: web::Form<FormData>,
+    pool: web::Data<PgPool>,
+    user_id: web::ReqData<UserId>,
+) -> HttpResponse {
+    let user_id = user_id.into_inner();
+
+    if form.new_password.expose_secret() != form.new_password_check.expose_secret() {
+        FlashMessage::error(
+            "You entered two different new passwords - the field values must match.",
+        )
+        .send();
+        return see_other("/admin/password");
+    }
+
+    let new_password_length = form.new_password.expose_secret().chars().count();
+    if !(MIN_PASSWORD_LENGTH..=MAX_PASSWORD_LENGTH).contains(&new_password_length) {
+        FlashMessage::error(format!(
+            "The new password must be between {} and {} characters long.",
+            MIN_PASSWORD_LENGTH, MAX_PASSWORD_LENGTH
+        ))
+        .send();
+        return see_other("/admin/password");
+    }
+
+    let username = match get_username(*user_id, &pool).await {
+        Ok(username) => username,
+        Err(e) => {
+            tracing::error!("{:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let credentials = Credentials {
+        username,
+        password: form.0.current_password,
+    };
+    match validate_credentials(credentials, &pool).await {
+        Ok(_) => {}
+        Err(AuthError::InvalidCredentials(_)) => {
+            FlashMessage::error("The current password is incorrect.").send();
+            return see_other("/admin/password");
+        }
+        Err(AuthError::UnexpectedError(e)) => {
+            tracing::error!("Failed to validate credentials: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    if let Err(e) = authentication::change_password(*user_id, form.0.new_password, &pool).await {
+        tracing::error!("{:?}", e);
+        return HttpResponse::InternalServerError().finish();
+    }
+    FlashMessage::info("Your password has been changed.").send();
+    see_other("/admin/password")
+}
@@ -1,5 +1,22 @@
-use actix_web::HttpResponse;
+use crate::email_client::{CircuitState, EmailClient};
+use actix_web::{web, HttpResponse};
 
-pub async fn health_check() -> HttpResponse {
-    HttpResponse::Ok().finish()
+#[derive(serde::Serialize)]
+struct HealthReport {
+    status: &'static str,
+    email_circuit_breaker: CircuitState,
+}
+
+// The application stays up (200) while the email provider is unavailable:
+// an open circuit breaker only marks it as degraded.
+pub async fn health_check(email_client: web::Data<EmailClient>) -> HttpResponse {
+    let email_circuit_breaker = email_client.circuit_state();
+    let status = match email_circuit_breaker {
+        CircuitState::Closed => "ok",
+        CircuitState::Open | CircuitState::HalfOpen => "degraded",
+    };
+    HttpResponse::Ok().json(HealthReport {
+        status,
+        email_circuit_breaker,
+    })
 }
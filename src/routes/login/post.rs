@@ -0,0 +1,51 @@
+use crate::authentication::{validate_credentials, AuthError, Credentials};
+use crate::session_state::TypedSession;
+use crate::utils::see_other;
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::FlashMessage;
+use secrecy::SecretString;
+use sqlx::PgPool;
+
+#[derive(serde::Deserialize)]
+pub struct FormData {
+    username: String,
+    password: SecretString,
+}
+
+#[tracing::instrument(
+    name = "Log in an operator",
+    skip(form, pool, session),
+    fields(username=tracing::field::Empty, user_id=tracing::field::Empty)
+)]
+pub async fn login(
+    form: web::Form<FormData>,
+    pool: web::Data<PgPool>,
+    session: TypedSession,
+) -> HttpResponse {
+    let credentials = Credentials {
+        username: form.0.username,
+        password: form.0.password,
+    };
+    tracing::Span::current().record("username", tracing::field::display(&credentials.username));
+
+    match validate_credentials(credentials, &pool).await {
+        Ok(user_id) => {
+            tracing::Span::current().record("user_id", tracing::field::display(&user_id));
+            session.renew();
+            if let Err(e) = session.insert_user_id(user_id) {
+                tracing::error!("Failed to store the user id in the session: {:?}", e);
+                return HttpResponse::InternalServerError().finish();
+            }
+            see_other("/admin/dashboard")
+        }
+        Err(AuthError::InvalidCredentials(e)) => {
+            tracing::warn!("Failed login attempt: {:?}", e);
+            FlashMessage::error("Authentication failed.").send();
+            see_other("/login")
+        }
+        Err(AuthError::UnexpectedError(e)) => {
+            tracing::error!("Failed to validate credentials: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use crate::email_client::EmailClient;
+use actix_web::{web, HttpResponse};
+use std::fmt::Write;
+
+// Prometheus text exposition format: scraped by the monitoring stack, no client library needed
+// for a handful of values.
+pub async fn metrics(email_client: web::Data<EmailClient>) -> HttpResponse {
+    let mut body = String::new();
+    if let Some(rate_limiter) = email_client.rate_limiter_metrics() {
+        let metrics = [
+            (
+                "email_rate_limiter_messages_total",
+                "counter",
+                "Emails let through the rate limiter.",
+                rate_limiter.messages as f64,
+            ),
+            (
+                "email_rate_limiter_throttled_total",
+                "counter",
+                "Emails which had to wait for the rate limiter.",
+                rate_limiter.throttled as f64,
+            ),
+            (
+                "email_rate_limiter_rejected_total",
+                "counter",
+                "Emails not sent because the rate limiter would have kept them waiting too long.",
+                rate_limiter.rejected as f64,
+            ),
+            (
+                "email_rate_limiter_wait_seconds_total",
+                "counter",
+                "Time spent waiting for the rate limiter.",
+                rate_limiter.wait.as_secs_f64(),
+            ),
+            (
+                "email_rate_limiter_available_tokens",
+                "gauge",
+                "Emails that can be sent right away.",
+                rate_limiter.available_tokens,
+            ),
+        ];
+        for (name, kind, help, value) in metrics {
+            // Writing to a String cannot fail
+            let _ = writeln!(body, "# HELP {} {}", name, help);
+            let _ = writeln!(body, "# TYPE {} {}", name, kind);
+            let _ = writeln!(body, "{} {}", name, value);
+        }
+    }
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body)
+}
@@ -1,7 +1,19 @@
+mod admin;
 mod health_check;
+mod login;
+mod metrics;
+mod newsletters;
 mod subscriptions;
 mod subscriptions_confirm;
+mod subscriptions_unsubscribe;
+mod webhooks;
 
+pub use admin::*;
 pub use health_check::*;
+pub use login::*;
+pub use metrics::*;
+pub use newsletters::*;
 pub use subscriptions::*;
 pub use subscriptions_confirm::*;
+pub use subscriptions_unsubscribe::*;
+pub use webhooks::*;
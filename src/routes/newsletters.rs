@@ -0,0 +1,211 @@
+use crate::authentication::UserId;
+use crate::email_templates::{EmailTemplates, RenderedEmail};
+use crate::idempotency::{
+    get_idempotency_key, save_response, try_processing, IdempotencyScope, NextAction,
+};
+use crate::issue_scheduler::enqueue_delivery_tasks;
+use crate::markdown;
+use crate::utils::error_chain_fmt;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+// Expected JSON payload for a newsletter issue, either
+// {"title": "...", "content": {"html": "...", "text": "..."}}
+// or {"title": "...", "content": {"markdown": "..."}}
+// An optional RFC 3339 "scheduled_at" date postpones the send.
+#[derive(serde::Deserialize)]
+pub struct BodyData {
+    title: String,
+    content: Content,
+    #[serde(default)]
+    scheduled_at: Option<DateTime<Utc>>,
+}
+
+#[derive(serde::Serialize)]
+struct PublishedIssue {
+    newsletter_issue_id: Uuid,
+    status: &'static str,
+    scheduled_at: Option<DateTime<Utc>>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+pub enum Content {
+    /// Both bodies written by hand: sent as they are
+    Bodies { html: String, text: String },
+    /// Rendered to sanitized HTML and to plain text, then wrapped in the issue layout
+    Markdown { markdown: String },
+}
+
+impl Content {
+    /// HTML and plain text bodies to be stored for the issue
+    pub fn render(
+        &self,
+        title: &str,
+        email_templates: &EmailTemplates,
+    ) -> Result<RenderedEmail, minijinja::Error> {
+        match self {
+            Content::Bodies { html, text } => Ok(RenderedEmail {
+                html: html.clone(),
+                text: text.clone(),
+            }),
+            Content::Markdown { markdown } => email_templates.issue_layout(
+                title,
+                &markdown::to_html(markdown),
+                &markdown::to_text(markdown),
+            ),
+        }
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum PublishError {
+    #[error("{0}")]
+    InvalidIdempotencyKey(String),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for PublishError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for PublishError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PublishError::InvalidIdempotencyKey(_) => StatusCode::BAD_REQUEST,
+            PublishError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            PublishError::UnexpectedError(_) => HttpResponse::build(self.status_code()).finish(),
+            _ => HttpResponse::build(self.status_code()).body(self.to_string()),
+        }
+    }
+}
+
+// Publishing does not send any email by itself: the issue is stored and one delivery task
+// per confirmed subscriber is enqueued. The background worker (see `issue_delivery_worker`)
+// takes care of the actual delivery, so a restart in the middle of a send loses nothing.
+// Issues scheduled in the future are only stored: the scheduler (see `issue_scheduler`)
+// enqueues them once their date is reached. Drafts are managed under `/admin/newsletters`.
+#[tracing::instrument(
+    name = "Publish a newsletter issue",
+    skip(request, body, pool, email_templates, user_id),
+    fields(newsletter_title=%body.title, user_id=%*user_id)
+)]
+pub async fn publish_newsletter(
+    request: HttpRequest,
+    body: web::Json<BodyData>,
+    pool: web::Data<PgPool>,
+    email_templates: web::Data<EmailTemplates>,
+    user_id: web::ReqData<UserId>,
+) -> Result<HttpResponse, PublishError> {
+    let content = body
+        .content
+        .render(&body.title, &email_templates)
+        .context("Failed to render the issue layout.")?;
+    let idempotency_key =
+        get_idempotency_key(&request).map_err(PublishError::InvalidIdempotencyKey)?;
+
+    // With an idempotency key, a retried publication replays the first response
+    // instead of enqueuing the issue a second time.
+    let scope = IdempotencyScope::new("/newsletters", Some(**user_id));
+    let mut transaction = match &idempotency_key {
+        Some(idempotency_key) => match try_processing(&pool, &scope, idempotency_key)
+            .await
+            .context("Failed to reserve the idempotency key.")?
+        {
+            NextAction::StartProcessing(transaction) => transaction,
+            NextAction::ReturnSavedResponse(saved_response) => return Ok(saved_response),
+        },
+        None => pool
+            .begin()
+            .await
+            .context("Failed to acquire a Postgres connection from the pool.")?,
+    };
+
+    // A date in the past simply means "now"
+    let scheduled_at = body.scheduled_at.filter(|date| *date > Utc::now());
+    let issue_id = insert_newsletter_issue(&mut transaction, &body.title, &content, scheduled_at)
+        .await
+        .context("Failed to store the newsletter issue details.")?;
+
+    let status = if scheduled_at.is_some() {
+        "scheduled"
+    } else {
+        enqueue_delivery_tasks(&mut transaction, issue_id)
+            .await
+            .context("Failed to enqueue the delivery tasks.")?
+    };
+
+    let response = HttpResponse::Ok().json(PublishedIssue {
+        newsletter_issue_id: issue_id,
+        status,
+        scheduled_at,
+    });
+    match idempotency_key {
+        // Saving the response commits the transaction
+        Some(idempotency_key) => Ok(
+            save_response(transaction, &scope, &idempotency_key, response)
+                .await
+                .context("Failed to save the response of the publication.")?,
+        ),
+        None => {
+            transaction
+                .commit()
+                .await
+                .context("Failed to commit the SQL transaction to publish a newsletter issue.")?;
+            Ok(response)
+        }
+    }
+}
+
+/// Store a new issue, as a draft or scheduled for `scheduled_at`
+#[tracing::instrument(
+    name = "Saving newsletter issue details in the database",
+    skip(transaction, title, content)
+)]
+pub async fn insert_newsletter_issue(
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    title: &str,
+    content: &RenderedEmail,
+    scheduled_at: Option<DateTime<Utc>>,
+) -> Result<Uuid, sqlx::Error> {
+    let newsletter_issue_id = Uuid::new_v4();
+    let status = if scheduled_at.is_some() {
+        "scheduled"
+    } else {
+        "draft"
+    };
+    sqlx::query!(
+        r#"INSERT INTO newsletter_issues (
+            newsletter_issue_id, title, text_content, html_content, published_at, scheduled_at,
+            status
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        "#,
+        newsletter_issue_id,
+        title,
+        content.text,
+        content.html,
+        Utc::now(),
+        scheduled_at,
+        status
+    )
+    .execute(transaction.as_mut())
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(newsletter_issue_id)
+}
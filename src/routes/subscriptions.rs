@@ -1,13 +1,25 @@
-use crate::startup::ApplicationBaseUrl;
+use crate::idempotency::{
+    get_idempotency_key, save_response, try_processing, IdempotencyScope, NextAction,
+};
+use crate::problem_details::{FieldError, ProblemDetails};
+use crate::startup::{ApplicationBaseUrl, SubscriptionTokenTtl};
+use crate::utils::error_chain_fmt;
 use crate::{
     domain::{NewSubscriber, SubscriberEmail, SubscriberName},
     email_client::EmailClient,
+    email_templates::EmailTemplates,
 };
-use actix_web::{web, HttpResponse};
+use actix_web::dev::Payload;
+use actix_web::http::StatusCode;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
 use chrono::Utc;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use sqlx::PgPool;
+use std::future::Future;
+use std::ops::Deref;
+use std::pin::Pin;
 use uuid::Uuid;
 
 // Defining the structure of the expected form data,
@@ -18,13 +30,154 @@ pub struct FormData {
     email: String,
 }
 
+/// Body of a subscription request: an HTML form post or a JSON document with the same fields.
+/// Any other content type is rejected with a `415 Unsupported Media Type`.
+pub struct SubscriptionBody(FormData);
+
+impl SubscriptionBody {
+    pub fn into_inner(self) -> FormData {
+        self.0
+    }
+}
+
+impl Deref for SubscriptionBody {
+    type Target = FormData;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl FromRequest for SubscriptionBody {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let essence = match req.mime_type() {
+            Ok(Some(mime)) => mime.essence_str().to_owned(),
+            _ => String::new(),
+        };
+        let req = req.clone();
+        let mut payload = payload.take();
+        Box::pin(async move {
+            match essence.as_str() {
+                "application/x-www-form-urlencoded" => {
+                    let form = web::Form::<FormData>::from_request(&req, &mut payload).await?;
+                    Ok(Self(form.into_inner()))
+                }
+                "application/json" => {
+                    let json = web::Json::<FormData>::from_request(&req, &mut payload).await?;
+                    Ok(Self(json.into_inner()))
+                }
+                _ => Err(actix_web::error::ErrorUnsupportedMediaType(
+                    "Subscriptions must be sent as `application/x-www-form-urlencoded` \
+                    or `application/json`.",
+                )),
+            }
+        })
+    }
+}
+
 impl TryFrom<FormData> for NewSubscriber {
-    type Error = String;
+    type Error = SubscribeError;
 
+    // Both fields are validated, so that the client learns about every problem at once
     fn try_from(value: FormData) -> Result<Self, Self::Error> {
-        let name = SubscriberName::parse(value.name)?;
-        let email = SubscriberEmail::parse(value.email)?;
-        Ok(Self { name, email })
+        match (
+            SubscriberName::parse(value.name),
+            SubscriberEmail::parse(value.email),
+        ) {
+            (Ok(name), Ok(email)) => Ok(Self { name, email }),
+            (name, email) => {
+                let mut errors = Vec::new();
+                if let Err(e) = name {
+                    errors.push(FieldError::new("name", e));
+                }
+                if let Err(e) = email {
+                    errors.push(FieldError::new("email", e));
+                }
+                Err(SubscribeError::ValidationError(errors))
+            }
+        }
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum SubscribeError {
+    /// The submitted data is invalid: the messages are sent back to the client
+    #[error("{}", .0.iter().map(|e| e.detail.as_str()).collect::<Vec<_>>().join("\n"))]
+    ValidationError(Vec<FieldError>),
+    #[error("{0}")]
+    InvalidIdempotencyKey(String),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl SubscribeError {
+    pub fn problem_details(&self) -> ProblemDetails {
+        let mut problem = ProblemDetails::from_status(self.status_code());
+        match self {
+            SubscribeError::ValidationError(errors) => {
+                problem.type_ = "/problems/invalid-subscriber".into();
+                problem.title = "The subscription request is invalid.".into();
+                problem.errors = errors.clone();
+            }
+            SubscribeError::InvalidIdempotencyKey(message) => {
+                problem.detail = Some(message.clone());
+            }
+            SubscribeError::UnexpectedError(_) => {}
+        }
+        problem
+    }
+}
+
+// The Debug representation is what ends up in the logs: show the whole chain of causes
+impl std::fmt::Debug for SubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for SubscribeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            SubscribeError::ValidationError(_) | SubscribeError::InvalidIdempotencyKey(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            SubscribeError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // Unexpected errors are logged by the tracing middleware, the client only gets the status code
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            SubscribeError::UnexpectedError(_) => HttpResponse::build(self.status_code()).finish(),
+            _ => HttpResponse::build(self.status_code()).body(self.to_string()),
+        }
+    }
+}
+
+/// Failure to persist a subscription token
+pub struct StoreTokenError(sqlx::Error);
+
+impl std::fmt::Display for StoreTokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "A database error was encountered while trying to store a subscription token."
+        )
+    }
+}
+
+impl std::fmt::Debug for StoreTokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl std::error::Error for StoreTokenError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
     }
 }
 
@@ -34,65 +187,199 @@ impl TryFrom<FormData> for NewSubscriber {
 // fields: custom fields to add to the span
 #[tracing::instrument(
     name = "Adding a new subscriber",
-    skip(form, pool, email_client, base_url),
+    skip(request, form, pool, email_client, email_templates, base_url, token_ttl),
     fields(
         subscriber_email=%form.email,
         subscriber_name=%form.name
     )
 )]
 pub async fn subscribe(
-    form: web::Form<FormData>,
+    request: HttpRequest,
+    form: SubscriptionBody,
     pool: web::Data<PgPool>,
     email_client: web::Data<EmailClient>,
+    email_templates: web::Data<EmailTemplates>,
     base_url: web::Data<ApplicationBaseUrl>,
-) -> HttpResponse {
-    // Why we are using form.0 instead of form.name?
-    // Because form is a smart pointer (web::Form) that wraps the actual data (FormData)
-    let new_subscriber = match form.0.try_into() {
-        Ok(subscriber) => subscriber,
-        Err(_) => return HttpResponse::BadRequest().finish(),
-    };
+    token_ttl: web::Data<SubscriptionTokenTtl>,
+) -> Result<HttpResponse, SubscribeError> {
+    // Forms and JSON documents go through the same validation
+    let new_subscriber: NewSubscriber = form.into_inner().try_into()?;
+    let idempotency_key =
+        get_idempotency_key(&request).map_err(SubscribeError::InvalidIdempotencyKey)?;
 
-    let mut transaction = match pool.begin().await {
-        Ok(transaction) => transaction,
-        Err(_) => return HttpResponse::InternalServerError().finish(),
+    // The key reservation stays uncommitted until the response is saved: a retry with the
+    // same key waits for it, and a failed request releases the key.
+    let scope = IdempotencyScope::new("/subscriptions", None);
+    let idempotency = match idempotency_key {
+        Some(idempotency_key) => match try_processing(&pool, &scope, &idempotency_key)
+            .await
+            .context("Failed to reserve the idempotency key.")?
+        {
+            NextAction::StartProcessing(transaction) => Some((idempotency_key, transaction)),
+            NextAction::ReturnSavedResponse(saved_response) => return Ok(saved_response),
+        },
+        None => None,
     };
 
-    let subscriber_id = match insert_subscriber(&mut transaction, &new_subscriber).await {
-        Ok(subscriber_id) => subscriber_id,
-        Err(_) => return HttpResponse::InternalServerError().finish(),
-    };
-    let subscription_token = generate_subscription_token();
-    if store_token(&mut transaction, subscriber_id, &subscription_token)
+    let mut transaction = pool
+        .begin()
         .await
-        .is_err()
+        .context("Failed to acquire a Postgres connection from the pool.")?;
+    // Inserting first, rather than looking the email up, makes a concurrent signup for the same
+    // address wait on the unique constraint, then take the existing subscriber path.
+    let subscription_token = match insert_subscriber(&mut transaction, &new_subscriber)
+        .await
+        .context("Failed to insert the new subscriber in the database.")?
     {
-        return HttpResponse::InternalServerError().finish();
+        Some(subscriber_id) => {
+            let subscription_token = generate_token();
+            store_token(
+                &mut transaction,
+                subscriber_id,
+                &subscription_token,
+                token_ttl.0,
+            )
+            .await
+            .context("Failed to store the confirmation token for a new subscriber.")?;
+            store_unsubscribe_token(&mut transaction, subscriber_id, &generate_token())
+                .await
+                .context("Failed to store the unsubscribe token for a new subscriber.")?;
+            Some(subscription_token)
+        }
+        None => subscribe_again(&mut transaction, &new_subscriber, token_ttl.0).await?,
+    };
+    // Committed before sending: the row lock is not held while we wait on the email provider,
+    // and an email whose outcome is unknown never carries a link to a rolled back token.
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to store a new subscriber.")?;
+
+    // If the email cannot be sent, the client can retry: the subscriber is still pending,
+    // so the retry sends the same confirmation link again.
+    if let Some(subscription_token) = subscription_token {
+        send_confirmation_email(
+            &email_client,
+            &email_templates,
+            new_subscriber,
+            &base_url.0,
+            &subscription_token,
+        )
+        .await
+        .context("Failed to send a confirmation email.")?;
     }
 
-    // Commit the transaction else it will be rolled back automatically when dropped
-    if transaction.commit().await.is_err() {
-        return HttpResponse::InternalServerError().finish();
+    let response = HttpResponse::Ok().finish();
+    let response = match idempotency {
+        // Saving the response commits the key reservation
+        Some((idempotency_key, transaction)) => {
+            save_response(transaction, &scope, &idempotency_key, response)
+                .await
+                .context("Failed to save the response for the idempotency key.")?
+        }
+        None => response,
+    };
+    Ok(response)
+}
+
+/// Handle a subscription request for an email which is already in the database.
+/// Returns the confirmation token to send, if any.
+async fn subscribe_again(
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    new_subscriber: &NewSubscriber,
+    token_ttl: std::time::Duration,
+) -> Result<Option<String>, anyhow::Error> {
+    let subscriber = get_subscriber_by_email(transaction, new_subscriber)
+        .await
+        .context("Failed to look for an existing subscriber with the same email.")?
+        .context("The subscriber with the same email has just been removed.")?;
+    match subscriber.status.as_str() {
+        // Already confirmed: nothing to do. We still answer with a 200,
+        // so that the endpoint cannot be used to find out who is on the list.
+        "confirmed" => Ok(None),
+        // The address bounced or reported us as spam: never email it again
+        "bounced" | "complained" => Ok(None),
+        // Still pending (or unsubscribed and coming back): send a confirmation email again
+        _ => {
+            mark_subscriber_as_pending(transaction, subscriber.id)
+                .await
+                .context("Failed to mark the subscriber as pending confirmation.")?;
+            let subscription_token = match get_token_from_subscriber_id(transaction, subscriber.id)
+                .await
+                .context("Failed to retrieve the confirmation token of a subscriber.")?
+            {
+                Some(subscription_token) => subscription_token,
+                None => {
+                    let subscription_token = generate_token();
+                    store_token(transaction, subscriber.id, &subscription_token, token_ttl)
+                        .await
+                        .context("Failed to store a new confirmation token for a subscriber.")?;
+                    subscription_token
+                }
+            };
+            Ok(Some(subscription_token))
+        }
     }
+}
 
-    if send_confirmation_email(
-        &email_client,
-        new_subscriber,
-        &base_url.0,
-        &subscription_token,
+// Separation of concerns: database interaction logic is separated from request handling logic
+
+pub struct ExistingSubscriber {
+    id: Uuid,
+    status: String,
+}
+
+#[tracing::instrument(
+    name = "Looking for an existing subscriber with the same email",
+    skip(new_subscriber, transaction)
+)]
+pub async fn get_subscriber_by_email(
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    new_subscriber: &NewSubscriber,
+) -> Result<Option<ExistingSubscriber>, sqlx::Error> {
+    sqlx::query_as!(
+        ExistingSubscriber,
+        r#"SELECT id, status FROM subscriptions WHERE email = $1 FOR UPDATE"#,
+        new_subscriber.email.as_ref(),
     )
+    .fetch_optional(transaction.as_mut())
     .await
-    .is_err()
-    {
-        tracing::error!("Failed to send welcome email");
-        return HttpResponse::InternalServerError().finish();
-    }
+}
 
-    HttpResponse::Ok().finish()
+#[tracing::instrument(name = "Mark subscriber as pending confirmation", skip(transaction))]
+pub async fn mark_subscriber_as_pending(
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE subscriptions SET status = 'pending_confirmation' WHERE id = $1"#,
+        subscriber_id
+    )
+    .execute(transaction.as_mut())
+    .await?;
+    Ok(())
 }
 
-// Separation of concerns: database interaction logic is separated from request handling logic
+// Only tokens that have not expired yet can be sent again
+#[tracing::instrument(name = "Get subscription token from subscriber_id", skip(transaction))]
+pub async fn get_token_from_subscriber_id(
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    subscriber_id: Uuid,
+) -> Result<Option<String>, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"SELECT subscription_token FROM subscription_tokens
+        WHERE subscriber_id = $1 AND expires_at > now()
+        ORDER BY expires_at DESC
+        LIMIT 1
+        "#,
+        subscriber_id
+    )
+    .fetch_optional(transaction.as_mut())
+    .await?;
+    Ok(result.map(|r| r.subscription_token))
+}
 
+// Returns `None` when a subscriber with the same email already exists
 #[tracing::instrument(
     name = "Saving new subscriber details in the database",
     skip(new_subscriber, transaction)
@@ -100,11 +387,12 @@ pub async fn subscribe(
 pub async fn insert_subscriber(
     transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
     new_subscriber: &NewSubscriber,
-) -> Result<Uuid, sqlx::Error> {
+) -> Result<Option<Uuid>, sqlx::Error> {
     let subscription_id = Uuid::new_v4();
-    sqlx::query!(
+    let n_inserted_rows = sqlx::query!(
         r#"INSERT INTO subscriptions (id, email, name, subscribed_at, status)
         VALUES ($1, $2, $3, $4, 'pending_confirmation')
+        ON CONFLICT (email) DO NOTHING
         "#,
         subscription_id,
         new_subscriber.email.as_ref(),
@@ -112,12 +400,9 @@ pub async fn insert_subscriber(
         Utc::now()
     )
     .execute(transaction.as_mut())
-    .await
-    .map_err(|e| {
-        tracing::error!("Failed to execute query: {:?}", e);
-        e
-    })?; // Using "?" to return early in case of error
-    Ok(subscription_id)
+    .await? // Using "?" to return early in case of error
+    .rows_affected();
+    Ok((n_inserted_rows > 0).then_some(subscription_id))
 }
 
 #[tracing::instrument(
@@ -128,56 +413,74 @@ pub async fn store_token(
     transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
     subscriber_id: Uuid,
     subscription_token: &str,
-) -> Result<(), sqlx::Error> {
+    ttl: std::time::Duration,
+) -> Result<(), StoreTokenError> {
+    let created_at = Utc::now();
+    let expires_at = created_at
+        + chrono::Duration::from_std(ttl).expect("The subscription token TTL is out of range");
     sqlx::query!(
-        r#"INSERT INTO subscription_tokens (subscription_token, subscriber_id)
-        VALUES ($1, $2)
+        r#"INSERT INTO subscription_tokens (subscription_token, subscriber_id, created_at, expires_at)
+        VALUES ($1, $2, $3, $4)
         "#,
         subscription_token,
-        subscriber_id
+        subscriber_id,
+        created_at,
+        expires_at
     )
     .execute(transaction.as_mut())
     .await
-    .map_err(|e| {
-        tracing::error!("Failed to execute query: {:?}", e);
-        e
-    })?;
+    .map_err(StoreTokenError)?;
+    Ok(())
+}
+
+// The unsubscribe token never expires: it is part of every issue sent to the subscriber
+#[tracing::instrument(
+    name = "Store unsubscribe token in the database",
+    skip(transaction, unsubscribe_token)
+)]
+pub async fn store_unsubscribe_token(
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    subscriber_id: Uuid,
+    unsubscribe_token: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"INSERT INTO unsubscribe_tokens (unsubscribe_token, subscriber_id) VALUES ($1, $2)"#,
+        unsubscribe_token,
+        subscriber_id
+    )
+    .execute(transaction.as_mut())
+    .await?;
     Ok(())
 }
 
 #[tracing::instrument(
     name = "Send a confirmation email to a new subscriber",
-    skip(email_client, new_subscriber)
+    skip(email_client, email_templates, new_subscriber)
 )]
 pub async fn send_confirmation_email(
     email_client: &EmailClient,
+    email_templates: &EmailTemplates,
     new_subscriber: NewSubscriber,
     base_url: &str,
     subscription_token: &str,
-) -> Result<(), reqwest::Error> {
+) -> Result<(), anyhow::Error> {
     let confirmation_link = format!(
         "{}/subscriptions/confirm?subscription_token={}",
         base_url, subscription_token
     );
-
-    let plain_body = format!(
-        "Welcome to our newsletter!\n\
-        Visit {} to confirm your subscription.",
-        confirmation_link
-    );
-    let html_body = format!(
-        "Welcome to our newsletter!<br />\
-        Click <a href=\"{}\">here</a> to confirm your subscription.",
-        confirmation_link
-    );
+    let body = email_templates
+        .confirmation_email(new_subscriber.name.as_ref(), &confirmation_link)
+        .context("Failed to render the confirmation email.")?;
 
     email_client
-        .send_email(new_subscriber.email, "Welcome!", &html_body, &plain_body)
-        .await
+        .send_email(new_subscriber.email, "Welcome!", &body.html, &body.text)
+        .await?;
+    Ok(())
 }
 
-/// Generate a random 25-character-long alphanumeric case-sensitive subscription token
-fn generate_subscription_token() -> String {
+/// Generate a random 25-character-long alphanumeric case-sensitive token,
+/// used both for confirmation and for unsubscribe links
+fn generate_token() -> String {
     let mut rng = thread_rng();
     std::iter::repeat_with(|| rng.sample(Alphanumeric))
         .map(char::from)
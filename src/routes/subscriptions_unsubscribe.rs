@@ -0,0 +1,189 @@
+use actix_multipart::form::text::Text;
+use actix_multipart::form::MultipartForm;
+use actix_web::dev::Payload;
+use actix_web::http::header::ContentType;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use sqlx::PgPool;
+use std::future::Future;
+use std::pin::Pin;
+use uuid::Uuid;
+
+#[derive(serde::Deserialize, Debug)]
+pub struct UnsubscribeParameters {
+    token: String,
+}
+
+// Link scanners and mail previews follow links: the GET only asks for a confirmation,
+// the form then POSTs to the one-click endpoint.
+#[tracing::instrument(
+    name = "Ask for an unsubscription confirmation",
+    skip(parameters, pool)
+)]
+pub async fn unsubscribe(
+    parameters: web::Query<UnsubscribeParameters>,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    match get_subscriber_id_from_unsubscribe_token(&pool, &parameters.token).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::Unauthorized().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+
+    let token = htmlescape::encode_attribute(&parameters.token);
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Unsubscribe</title>
+</head>
+<body>
+    <p>Do you want to stop receiving newsletter issues?</p>
+    <form action="/subscriptions/unsubscribe?token={token}" method="post">
+        <input type="hidden" name="List-Unsubscribe" value="One-Click">
+        <button type="submit">Unsubscribe</button>
+    </form>
+</body>
+</html>"#,
+        ))
+}
+
+// Body sent by mailbox providers for one-click unsubscriptions (RFC 8058)
+#[derive(serde::Deserialize)]
+pub struct OneClickFormData {
+    #[serde(rename = "List-Unsubscribe")]
+    list_unsubscribe: String,
+}
+
+#[derive(MultipartForm)]
+struct OneClickMultipartData {
+    #[multipart(rename = "List-Unsubscribe")]
+    list_unsubscribe: Text<String>,
+}
+
+/// Body of a one-click unsubscription.
+/// RFC 8058 asks for `multipart/form-data`, but some providers send an URL-encoded form:
+/// both are accepted, anything else is rejected with a `415 Unsupported Media Type`.
+pub struct OneClickBody(OneClickFormData);
+
+impl FromRequest for OneClickBody {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let essence = match req.mime_type() {
+            Ok(Some(mime)) => mime.essence_str().to_owned(),
+            _ => String::new(),
+        };
+        let req = req.clone();
+        let mut payload = payload.take();
+        Box::pin(async move {
+            match essence.as_str() {
+                "application/x-www-form-urlencoded" => {
+                    let form =
+                        web::Form::<OneClickFormData>::from_request(&req, &mut payload).await?;
+                    Ok(Self(form.into_inner()))
+                }
+                "multipart/form-data" => {
+                    let form =
+                        MultipartForm::<OneClickMultipartData>::from_request(&req, &mut payload)
+                            .await?;
+                    Ok(Self(OneClickFormData {
+                        list_unsubscribe: form.into_inner().list_unsubscribe.into_inner(),
+                    }))
+                }
+                _ => Err(actix_web::error::ErrorUnsupportedMediaType(
+                    "One-click unsubscriptions must be sent as `multipart/form-data` \
+                    or `application/x-www-form-urlencoded`.",
+                )),
+            }
+        })
+    }
+}
+
+// Target of the `List-Unsubscribe-Post` header and of the confirmation form.
+// Mailbox providers POST without a session: the token alone identifies the subscriber.
+#[tracing::instrument(
+    name = "One-click unsubscribe a subscriber",
+    skip(parameters, form, pool)
+)]
+pub async fn unsubscribe_one_click(
+    parameters: web::Query<UnsubscribeParameters>,
+    form: OneClickBody,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    if form.0.list_unsubscribe != "One-Click" {
+        return HttpResponse::BadRequest().finish();
+    }
+    let subscriber_id =
+        match get_subscriber_id_from_unsubscribe_token(&pool, &parameters.token).await {
+            Ok(Some(subscriber_id)) => subscriber_id,
+            Ok(None) => return HttpResponse::Unauthorized().finish(),
+            Err(_) => return HttpResponse::InternalServerError().finish(),
+        };
+
+    if mark_subscriber_as_unsubscribed(&pool, subscriber_id)
+        .await
+        .is_err()
+    {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    HttpResponse::Ok().content_type(ContentType::html()).body(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Unsubscribed</title>
+</head>
+<body>
+    <p>You have been unsubscribed. You will not receive any more newsletter issues.</p>
+</body>
+</html>"#,
+    )
+}
+
+#[tracing::instrument(
+    name = "Get subscriber_id from unsubscribe token",
+    skip(pool, unsubscribe_token)
+)]
+pub async fn get_subscriber_id_from_unsubscribe_token(
+    pool: &PgPool,
+    unsubscribe_token: &str,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"SELECT subscriber_id FROM unsubscribe_tokens WHERE unsubscribe_token = $1"#,
+        unsubscribe_token
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(result.map(|r| r.subscriber_id))
+}
+
+// Unsubscribing twice (or after the subscriber has been removed) is not an error.
+// A bounce or a spam complaint is kept: an `unsubscribed` reader may subscribe again.
+#[tracing::instrument(name = "Mark subscriber as unsubscribed", skip(pool))]
+pub async fn mark_subscriber_as_unsubscribed(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE subscriptions SET status = 'unsubscribed'
+        WHERE id = $1 AND status NOT IN ('bounced', 'complained')
+        "#,
+        subscriber_id
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use crate::utils::error_chain_fmt;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use anyhow::Context;
+use sqlx::PgPool;
+
+/// Notification posted by Postmark.
+/// Only bounces and spam complaints matter to us: the other record types
+/// (deliveries, opens, clicks...) are acknowledged and ignored.
+#[derive(serde::Deserialize, Debug)]
+#[serde(tag = "RecordType")]
+pub enum PostmarkEvent {
+    Bounce {
+        #[serde(rename = "Type")]
+        kind: String,
+        #[serde(rename = "Email")]
+        email: String,
+        // Postmark deactivates the address after a hard bounce
+        #[serde(rename = "Inactive", default)]
+        inactive: bool,
+    },
+    SpamComplaint {
+        #[serde(rename = "Email")]
+        email: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(thiserror::Error)]
+pub enum WebhookError {
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for WebhookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for WebhookError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            WebhookError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+// Postmark retries the notifications it could not deliver: anything we do not act upon
+// (soft bounces, unknown addresses, other record types) still gets a `200 OK`.
+#[tracing::instrument(name = "Handle a Postmark notification", skip(event, pool))]
+pub async fn postmark_webhook(
+    event: web::Json<PostmarkEvent>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, WebhookError> {
+    match event.into_inner() {
+        PostmarkEvent::Bounce {
+            kind,
+            email,
+            inactive,
+        } => {
+            if kind == "HardBounce" || inactive {
+                mark_subscriber_as_bounced(&pool, &email)
+                    .await
+                    .context("Failed to mark the subscriber as bounced.")?;
+            } else {
+                tracing::info!(bounce_type = %kind, "Ignoring a transient bounce");
+            }
+        }
+        PostmarkEvent::SpamComplaint { email } => {
+            mark_subscriber_as_complained(&pool, &email)
+                .await
+                .context("Failed to mark the subscriber as complained.")?;
+        }
+        PostmarkEvent::Other => {}
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+// Only confirmed subscribers receive issues: once bounced, the address is skipped.
+// A spam complaint is the stronger signal and is not overwritten by a later bounce.
+#[tracing::instrument(name = "Mark subscriber as bounced", skip(pool))]
+async fn mark_subscriber_as_bounced(pool: &PgPool, email: &str) -> Result<(), sqlx::Error> {
+    let result = sqlx::query!(
+        r#"UPDATE subscriptions SET status = 'bounced' WHERE email = $1 AND status <> 'complained'"#,
+        email
+    )
+    .execute(pool)
+    .await?;
+    if result.rows_affected() == 0 {
+        tracing::warn!("The bounced address does not belong to any subscriber");
+    }
+    Ok(())
+}
+
+#[tracing::instrument(name = "Mark subscriber as complained", skip(pool))]
+async fn mark_subscriber_as_complained(pool: &PgPool, email: &str) -> Result<(), sqlx::Error> {
+    let result = sqlx::query!(
+        r#"UPDATE subscriptions SET status = 'complained' WHERE email = $1"#,
+        email
+    )
+    .execute(pool)
+    .await?;
+    if result.rows_affected() == 0 {
+        tracing::warn!("The complaining address does not belong to any subscriber");
+    }
+    Ok(())
+}
@@ -1,10 +1,33 @@
+use crate::authentication::{
+    create_first_operator, reject_anonymous_users, reject_invalid_credentials,
+    reject_invalid_webhook_credentials, Credentials, WebhookCredentials,
+};
 use crate::configuration::{DatabaseSettings, Settings};
-use crate::email_client::EmailClient;
-use crate::routes::{confirm, health_check, subscribe};
+use crate::email_client::{EmailClient, RetryPolicy};
+use crate::email_templates::EmailTemplates;
+use crate::issue_delivery_worker::IssueDeliveryWorker;
+use crate::issue_scheduler::IssueScheduler;
+use crate::problem_details::problem_json_errors;
+use crate::routes::{
+    admin_dashboard, cancel_scheduled_issue, change_password, change_password_form, confirm,
+    create_draft, health_check, issues_report, log_out, login, login_form, metrics,
+    postmark_webhook, preview_issue, publish_newsletter, reschedule_issue, send_issue,
+    send_test_issue, subscribe, unsubscribe, unsubscribe_one_click, update_draft,
+};
+use crate::session_store::PgSessionStore;
+use crate::subscription_sweeper::SubscriptionSweeper;
+use actix_session::SessionMiddleware;
+use actix_web::cookie::Key;
 use actix_web::dev::Server;
+use actix_web::middleware::from_fn;
 use actix_web::{web, App, HttpServer};
+use actix_web_flash_messages::storage::CookieMessageStore;
+use actix_web_flash_messages::FlashMessagesFramework;
+use secrecy::{ExposeSecret, SecretString};
 use sqlx::PgPool;
 use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::Duration;
 use tracing_actix_web::TracingLogger;
 
 pub async fn get_connection_pool(configuration: &DatabaseSettings) -> PgPool {
@@ -26,24 +49,65 @@ pub async fn get_connection_pool(configuration: &DatabaseSettings) -> PgPool {
 pub struct Application {
     port: u16,
     server: Server,
+    worker: IssueDeliveryWorker,
+    scheduler: IssueScheduler,
+    sweeper: SubscriptionSweeper,
 }
 
 impl Application {
     pub async fn build(configuration: Settings) -> Result<Self, std::io::Error> {
+        // Refuse to start rather than signing cookies with a key anybody could guess
+        if configuration.application.hmac_secret.expose_secret().len() < 64 {
+            return Err(std::io::Error::other(
+                "The HMAC secret (APP_APPLICATION__HMAC_SECRET) must be at least 64 bytes long",
+            ));
+        }
+
         // Set up the db pool connection
         let connection_pool = get_connection_pool(&configuration.database).await;
 
+        // Make sure somebody can log in to a fresh deployment
+        create_first_operator(
+            &configuration.application.initial_operator_username,
+            configuration.application.initial_operator_password.clone(),
+            &connection_pool,
+        )
+        .await
+        .map_err(std::io::Error::other)?;
+
         // Set up the email client
-        let sender_email = configuration
+        let email_client = configuration
             .email_client
-            .sender()
-            .expect("Invalid sender email address.");
-        let timeout = configuration.email_client.timeout();
-        let email_client = EmailClient::new(
-            configuration.email_client.base_url,
-            sender_email,
-            configuration.email_client.authorization_token,
-            timeout,
+            .client()
+            .map_err(std::io::Error::other)?;
+        let email_templates = Arc::new(EmailTemplates::new(
+            &configuration.application.templates_directory,
+        ));
+
+        // Set up the background worker delivering newsletter issues.
+        // It shares the email client (and its circuit breaker) with the HTTP server, but
+        // retries failed deliveries through the queue rather than while holding the task lock,
+        // timeouts included when `retry_timeouts` is set.
+        let worker = IssueDeliveryWorker::new(
+            connection_pool.clone(),
+            email_client
+                .clone()
+                .with_retry_policy(RetryPolicy::no_retry()),
+            email_templates.clone(),
+            configuration.application.base_url.clone(),
+            configuration.email_client.retry_timeouts,
+        );
+
+        // Set up the background scheduler of newsletter issues
+        let scheduler = IssueScheduler::new(
+            connection_pool.clone(),
+            configuration.application.issue_scheduler_interval(),
+        );
+
+        // Set up the background sweep of expired confirmation links
+        let sweeper = SubscriptionSweeper::new(
+            connection_pool.clone(),
+            configuration.application.unconfirmed_subscriber_retention(),
         );
 
         // Get the port number
@@ -52,16 +116,28 @@ impl Application {
             configuration.application.host, configuration.application.port
         );
         let listener = TcpListener::bind(address)?;
+        let subscription_token_ttl = configuration.application.subscription_token_ttl();
+        let webhook_credentials = configuration.email_client.webhook_credentials();
         let port = listener.local_addr()?.port();
         let server = run(
             listener,
             connection_pool,
             email_client,
+            email_templates,
             configuration.application.base_url,
+            configuration.application.hmac_secret,
+            subscription_token_ttl,
+            webhook_credentials,
         )?;
 
-        // We save the port number and server instance for later use
-        Ok(Self { port, server })
+        // We save the port number, server and worker instances for later use
+        Ok(Self {
+            port,
+            server,
+            worker,
+            scheduler,
+            sweeper,
+        })
     }
 
     // getter for port number
@@ -69,9 +145,14 @@ impl Application {
         self.port
     }
 
-    // run the server until it is stopped
+    // run the server and the background tasks until either of them is stopped
     pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
-        self.server.await
+        tokio::select! {
+            outcome = self.server => outcome,
+            outcome = self.worker.run_until_stopped() => outcome,
+            outcome = self.scheduler.run_until_stopped() => outcome,
+            outcome = self.sweeper.run_until_stopped() => outcome,
+        }
     }
 }
 
@@ -81,30 +162,120 @@ impl Application {
 // By defining a new type, we ensure that there are no conflicts with other String dependencies.
 pub struct ApplicationBaseUrl(pub String);
 
+// How long a confirmation link stays valid
+pub struct SubscriptionTokenTtl(pub Duration);
+
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     listener: TcpListener,
     db_pool: PgPool,
     email_client: EmailClient,
+    email_templates: Arc<EmailTemplates>,
     base_url: String,
+    hmac_secret: SecretString,
+    subscription_token_ttl: Duration,
+    webhook_credentials: Credentials,
 ) -> Result<Server, std::io::Error> {
+    // Sessions and flash messages are stored in signed cookies: they share the same key
+    let secret_key = Key::from(hmac_secret.expose_secret().as_bytes());
+    let message_store = CookieMessageStore::builder(secret_key.clone()).build();
+    let message_framework = FlashMessagesFramework::builder(message_store).build();
+    let session_store = PgSessionStore::new(db_pool.clone());
+
     // web::Data is a smart pointer Arc<T> around a type T that allows sharing
     // state across different handlers in a thread-safe way.
     // With this, we have a cheap clone of the pointer instead of cloning the whole connection,
     // and only one connection is created for the whole application.
     let db_pool = web::Data::new(db_pool);
     let email_client = web::Data::new(email_client);
+    let email_templates = web::Data::from(email_templates);
     let base_url = web::Data::new(ApplicationBaseUrl(base_url));
+    let subscription_token_ttl = web::Data::new(SubscriptionTokenTtl(subscription_token_ttl));
+    let webhook_credentials = web::Data::new(WebhookCredentials(webhook_credentials));
 
     // Beware: app instance is created for each worker thread -  the cost of a string allocation (or a pointer clone) is negligible compared to the cost of handling a request - so it's ok to clone the db_pool here
     let server = HttpServer::new(move || {
         App::new()
+            .wrap(message_framework.clone())
+            .wrap(SessionMiddleware::new(
+                session_store.clone(),
+                secret_key.clone(),
+            ))
             .wrap(TracingLogger::default())
             .route("/health_check", web::get().to(health_check))
-            .route("/subscriptions", web::post().to(subscribe))
+            .route("/login", web::get().to(login_form))
+            .route("/login", web::post().to(login))
+            .service(
+                // Every admin page requires a logged-in operator
+                web::scope("/admin")
+                    .wrap(from_fn(reject_anonymous_users))
+                    .route("/dashboard", web::get().to(admin_dashboard))
+                    .route("/password", web::get().to(change_password_form))
+                    .route("/password", web::post().to(change_password))
+                    .route("/logout", web::post().to(log_out))
+                    .route("/newsletters", web::get().to(issues_report))
+                    .route("/newsletters", web::post().to(create_draft))
+                    .route(
+                        "/newsletters/{newsletter_issue_id}",
+                        web::put().to(update_draft),
+                    )
+                    .route(
+                        "/newsletters/{newsletter_issue_id}/preview",
+                        web::get().to(preview_issue),
+                    )
+                    .route(
+                        "/newsletters/{newsletter_issue_id}/test",
+                        web::post().to(send_test_issue),
+                    )
+                    .route(
+                        "/newsletters/{newsletter_issue_id}/schedule",
+                        web::put().to(reschedule_issue),
+                    )
+                    .route(
+                        "/newsletters/{newsletter_issue_id}/schedule",
+                        web::delete().to(cancel_scheduled_issue),
+                    )
+                    .route(
+                        "/newsletters/{newsletter_issue_id}/send",
+                        web::post().to(send_issue),
+                    ),
+            )
+            .service(
+                // API clients asking for JSON get problem documents when something goes wrong
+                web::resource("/subscriptions")
+                    .wrap(from_fn(problem_json_errors))
+                    .route(web::post().to(subscribe)),
+            )
             .route("/subscriptions/confirm", web::get().to(confirm))
+            .route("/subscriptions/unsubscribe", web::get().to(unsubscribe))
+            .route(
+                "/subscriptions/unsubscribe",
+                web::post().to(unsubscribe_one_click),
+            )
+            .service(
+                // Publishing is restricted to operators
+                web::resource("/newsletters")
+                    .wrap(from_fn(reject_invalid_credentials))
+                    .route(web::post().to(publish_newsletter)),
+            )
+            .service(
+                // Scraped with operator credentials: the counters are not meant for the public
+                web::resource("/metrics")
+                    .wrap(from_fn(reject_invalid_credentials))
+                    .route(web::get().to(metrics)),
+            )
+            .service(
+                // Bounce and spam complaint notifications from Postmark
+                web::resource("/webhooks/postmark")
+                    .wrap(from_fn(reject_invalid_webhook_credentials))
+                    .route(web::post().to(postmark_webhook)),
+            )
             .app_data(db_pool.clone()) // Register the DB connection as part of the application state: stateful remember of the DB connection
             .app_data(email_client.clone()) // Register the email client as part of the application state
+            .app_data(email_templates.clone())
             .app_data(base_url.clone())
+            .app_data(subscription_token_ttl.clone())
+            .app_data(webhook_credentials.clone())
     })
     .listen(listener)?
     .run();
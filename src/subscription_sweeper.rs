@@ -0,0 +1,97 @@
+use std::time::Duration;
+
+use crate::session_store::delete_expired_sessions;
+use chrono::Utc;
+use sqlx::PgPool;
+
+/// How often expired confirmation links and sessions are cleaned up
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct SweepOutcome {
+    pub deleted_tokens: u64,
+    pub deleted_subscribers: u64,
+}
+
+/// Background task removing long expired subscription tokens and the subscribers
+/// who never confirmed their address. Expired admin sessions go in the same sweep.
+///
+/// Every replica runs its own sweeper: the deletions are idempotent, so overlapping
+/// sweeps are harmless.
+pub struct SubscriptionSweeper {
+    pool: PgPool,
+    unconfirmed_subscriber_retention: Duration,
+}
+
+impl SubscriptionSweeper {
+    pub fn new(pool: PgPool, unconfirmed_subscriber_retention: Duration) -> Self {
+        Self {
+            pool,
+            unconfirmed_subscriber_retention,
+        }
+    }
+
+    /// Sweep once per `SWEEP_INTERVAL`, forever.
+    pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
+        loop {
+            // Failures are already logged: we simply try again at the next tick
+            let _ = sweep(&self.pool, self.unconfirmed_subscriber_retention).await;
+            let _ = delete_expired_sessions(&self.pool).await;
+            tokio::time::sleep(SWEEP_INTERVAL).await;
+        }
+    }
+}
+
+/// Delete the tokens which expired more than `retention` ago, then the pending subscribers
+/// older than `retention` who are left without any token.
+///
+/// Expired tokens are kept for the retention window, so that their links keep being told
+/// apart from unknown ones (`410 Gone` rather than `401 Unauthorized`).
+#[tracing::instrument(name = "Sweep expired subscription tokens", skip(pool), err)]
+pub async fn sweep(pool: &PgPool, retention: Duration) -> Result<SweepOutcome, sqlx::Error> {
+    let cutoff = Utc::now()
+        - chrono::Duration::from_std(retention)
+            .expect("The unconfirmed subscriber retention is out of range");
+    let mut transaction = pool.begin().await?;
+    let deleted_tokens = sqlx::query!(
+        r#"DELETE FROM subscription_tokens WHERE expires_at < $1"#,
+        cutoff
+    )
+    .execute(transaction.as_mut())
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .rows_affected();
+
+    let deleted_subscribers = sqlx::query!(
+        r#"DELETE FROM subscriptions
+        WHERE status = 'pending_confirmation'
+            AND subscribed_at < $1
+            AND NOT EXISTS (
+                SELECT 1 FROM subscription_tokens
+                WHERE subscription_tokens.subscriber_id = subscriptions.id
+            )
+        "#,
+        cutoff
+    )
+    .execute(transaction.as_mut())
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .rows_affected();
+    transaction.commit().await?;
+
+    tracing::info!(
+        deleted_tokens,
+        deleted_subscribers,
+        "Swept expired subscription tokens"
+    );
+    Ok(SweepOutcome {
+        deleted_tokens,
+        deleted_subscribers,
+    })
+}
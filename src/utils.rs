@@ -0,0 +1,25 @@
+use actix_web::http::header::LOCATION;
+use actix_web::HttpResponse;
+
+/// `303 See Other` redirect, used to send the browser to a new page after a form submission
+pub fn see_other(location: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .insert_header((LOCATION, location))
+        .finish()
+}
+
+/// Write an error followed by its whole chain of causes, one per line.
+/// Used by the `Debug` implementations of our error types, so that the logs show
+/// the root cause and not only the outermost message.
+pub fn error_chain_fmt(
+    e: &impl std::error::Error,
+    f: &mut std::fmt::Formatter<'_>,
+) -> std::fmt::Result {
+    writeln!(f, "{}\n", e)?;
+    let mut current = e.source();
+    while let Some(cause) = current {
+        writeln!(f, "Caused by:\n\t{}", cause)?;
+        current = cause.source();
+    }
+    Ok(())
+}
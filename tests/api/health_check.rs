@@ -1,4 +1,6 @@
 use crate::helpers::spawn_app;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
 
 // `tokio::test` is the testing equivalent of `tokio::main`.
 // It also spares you from having to specify the `#[test]` attribute.
@@ -22,7 +24,41 @@ async fn health_check_works() {
 
     // Assert
     assert!(response.status().is_success());
-    assert_eq!(Some(0), response.content_length());
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(report["status"], "ok");
+    assert_eq!(report["email_circuit_breaker"], "closed");
+}
+
+#[tokio::test]
+async fn health_check_reports_an_open_email_circuit_breaker() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(503))
+        .mount(&app.email_server)
+        .await;
+    // Each subscription fails to send its confirmation email
+    // until the circuit breaker opens (5 consecutive failures in the base configuration)
+    for i in 0..5 {
+        let body = format!("name=le%20guin&email=ursula{}%40gmail.com", i);
+        let response = app.post_subscriptions(body).await;
+        assert_eq!(response.status().as_u16(), 500);
+    }
+
+    // Act
+    let response = app
+        .api_client
+        .get(format!("{}/health_check", &app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(report["status"], "degraded");
+    assert_eq!(report["email_circuit_breaker"], "open");
 }
 
 // #[test]
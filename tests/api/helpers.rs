@@ -1,7 +1,9 @@
 use once_cell::sync::Lazy;
+use secrecy::{ExposeSecret, SecretString};
 use sqlx::{Connection, Executor, PgConnection, PgPool};
 use uuid::Uuid;
-use zero2prod::configuration::{get_configuration, DatabaseSettings};
+use zero2prod::authentication::compute_password_hash;
+use zero2prod::configuration::{get_configuration, DatabaseSettings, EmailBackend};
 use zero2prod::startup::{get_connection_pool, Application};
 use zero2prod::telemetry::{get_subscriber, init_subscriber};
 
@@ -20,11 +22,69 @@ static TRACING: Lazy<()> = Lazy::new(|| {
     }
 });
 
+/// Answer a Postmark batch request with a successful result for each of its messages
+pub struct AcceptEveryMessage;
+
+impl wiremock::Respond for AcceptEveryMessage {
+    fn respond(&self, request: &wiremock::Request) -> wiremock::ResponseTemplate {
+        let messages: Vec<serde_json::Value> = serde_json::from_slice(&request.body).unwrap();
+        let results: Vec<_> = messages
+            .iter()
+            .map(|message| {
+                serde_json::json!({
+                    "ErrorCode": 0,
+                    "Message": "OK",
+                    "MessageID": Uuid::new_v4().to_string(),
+                    "To": message["To"]
+                })
+            })
+            .collect();
+        wiremock::ResponseTemplate::new(200).set_body_json(results)
+    }
+}
+
 pub struct TestApp {
     pub address: String,
     pub port: u16,
     pub db_pool: PgPool,
     pub email_server: wiremock::MockServer,
+    pub test_user: TestUser,
+    // Shared client: it keeps the session cookies across requests and does not follow redirects
+    pub api_client: reqwest::Client,
+    // Credentials expected on the Postmark webhook
+    pub webhook_username: String,
+    pub webhook_password: String,
+}
+
+/// Operator account created for each test application
+pub struct TestUser {
+    pub user_id: Uuid,
+    pub username: String,
+    pub password: String,
+}
+
+impl TestUser {
+    pub fn generate() -> Self {
+        Self {
+            user_id: Uuid::new_v4(),
+            username: Uuid::new_v4().to_string(),
+            password: Uuid::new_v4().to_string(),
+        }
+    }
+
+    pub async fn store(&self, pool: &PgPool) {
+        let password_hash = compute_password_hash(SecretString::from(self.password.clone()))
+            .expect("Failed to hash the test user password");
+        sqlx::query!(
+            "INSERT INTO users (user_id, username, password_hash) VALUES ($1, $2, $3)",
+            self.user_id,
+            self.username,
+            password_hash.expose_secret(),
+        )
+        .execute(pool)
+        .await
+        .expect("Failed to store test user.");
+    }
 }
 
 /// Links embedded in the confirmation email
@@ -33,9 +93,15 @@ pub struct ConfirmationLinks {
     pub plain_text: reqwest::Url,
 }
 
+/// Unsubscribe links embedded in a newsletter issue
+pub struct UnsubscribeLinks {
+    pub html: reqwest::Url,
+    pub plain_text: reqwest::Url,
+}
+
 impl TestApp {
     pub async fn post_subscriptions(&self, body: String) -> reqwest::Response {
-        reqwest::Client::new()
+        self.api_client
             .post(format!("{}/subscriptions", &self.address))
             .header("Content-Type", "application/x-www-form-urlencoded")
             .body(body)
@@ -44,32 +110,362 @@ impl TestApp {
             .expect("Failed to execute request.")
     }
 
+    pub async fn post_subscriptions_json(&self, body: &serde_json::Value) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/subscriptions", &self.address))
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// Same as `post_subscriptions`, from a client asking for JSON responses
+    pub async fn post_subscriptions_accepting_json(&self, body: String) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/subscriptions", &self.address))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .header("Accept", "application/json")
+            .body(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_subscriptions_with_idempotency_key(
+        &self,
+        body: String,
+        idempotency_key: &str,
+    ) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/subscriptions", &self.address))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .header("Idempotency-Key", idempotency_key)
+            .body(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_newsletters_with_idempotency_key(
+        &self,
+        body: serde_json::Value,
+        idempotency_key: &str,
+    ) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/newsletters", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .header("Idempotency-Key", idempotency_key)
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_postmark_webhook(&self, body: &serde_json::Value) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/webhooks/postmark", &self.address))
+            .basic_auth(&self.webhook_username, Some(&self.webhook_password))
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// Submit the confirmation form of an unsubscribe link
+    pub async fn post_unsubscribe(&self, unsubscribe_link: reqwest::Url) -> reqwest::Response {
+        self.api_client
+            .post(unsubscribe_link)
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body("List-Unsubscribe=One-Click")
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_newsletters(&self, body: serde_json::Value) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/newsletters", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_metrics(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/metrics", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// Wait for the background delivery worker to drain the issue delivery queue.
+    ///
+    /// Rows are only deleted once the corresponding email has been sent,
+    /// so an empty queue means every delivery has been attempted.
+    pub async fn wait_for_pending_deliveries(&self) {
+        for _ in 0..50 {
+            let pending =
+                sqlx::query_scalar!(r#"SELECT COUNT(*) as "count!" FROM issue_delivery_queue"#)
+                    .fetch_one(&self.db_pool)
+                    .await
+                    .expect("Failed to count pending deliveries");
+            if pending == 0 {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        panic!("The issue delivery queue has not been drained in time.");
+    }
+
+    pub async fn post_login<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!("{}/login", &self.address))
+            // This `reqwest` method makes sure that the body is URL-encoded
+            // and the `Content-Type` header is set accordingly.
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_login_html(&self) -> String {
+        self.api_client
+            .get(format!("{}/login", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn get_admin_dashboard(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/dashboard", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_admin_dashboard_html(&self) -> String {
+        self.get_admin_dashboard().await.text().await.unwrap()
+    }
+
+    pub async fn get_change_password(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/password", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_change_password_html(&self) -> String {
+        self.get_change_password().await.text().await.unwrap()
+    }
+
+    pub async fn post_change_password<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!("{}/admin/password", &self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// Log the test user in, sharing the session cookie with the following requests
+    pub async fn login(&self) {
+        let login_body = serde_json::json!({
+            "username": &self.test_user.username,
+            "password": &self.test_user.password
+        });
+        let response = self.post_login(&login_body).await;
+        assert_is_redirect_to(&response, "/admin/dashboard");
+    }
+
+    pub async fn get_issues_report(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/newsletters", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_draft(&self, body: &serde_json::Value) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/admin/newsletters", &self.address))
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn put_draft(
+        &self,
+        newsletter_issue_id: &str,
+        body: &serde_json::Value,
+    ) -> reqwest::Response {
+        self.api_client
+            .put(format!(
+                "{}/admin/newsletters/{}",
+                &self.address, newsletter_issue_id
+            ))
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_issue_preview(&self, newsletter_issue_id: &str) -> reqwest::Response {
+        self.api_client
+            .get(format!(
+                "{}/admin/newsletters/{}/preview",
+                &self.address, newsletter_issue_id
+            ))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_test_issue(
+        &self,
+        newsletter_issue_id: &str,
+        body: &serde_json::Value,
+    ) -> reqwest::Response {
+        self.api_client
+            .post(format!(
+                "{}/admin/newsletters/{}/test",
+                &self.address, newsletter_issue_id
+            ))
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_send_issue(&self, newsletter_issue_id: &str) -> reqwest::Response {
+        self.api_client
+            .post(format!(
+                "{}/admin/newsletters/{}/send",
+                &self.address, newsletter_issue_id
+            ))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// Lifecycle status of a newsletter issue
+    pub async fn issue_status(&self, newsletter_issue_id: &str) -> String {
+        sqlx::query_scalar!(
+            "SELECT status FROM newsletter_issues WHERE newsletter_issue_id = $1",
+            Uuid::parse_str(newsletter_issue_id).unwrap()
+        )
+        .fetch_one(&self.db_pool)
+        .await
+        .expect("Failed to fetch the issue status")
+    }
+
+    pub async fn put_issue_schedule(
+        &self,
+        newsletter_issue_id: &str,
+        body: &serde_json::Value,
+    ) -> reqwest::Response {
+        self.api_client
+            .put(format!(
+                "{}/admin/newsletters/{}/schedule",
+                &self.address, newsletter_issue_id
+            ))
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn delete_issue_schedule(&self, newsletter_issue_id: &str) -> reqwest::Response {
+        self.api_client
+            .delete(format!(
+                "{}/admin/newsletters/{}/schedule",
+                &self.address, newsletter_issue_id
+            ))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_logout(&self) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/admin/logout", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// Move the expiry date of every stored subscription token into the past
+    pub async fn expire_subscription_tokens(&self) {
+        sqlx::query!(
+            "UPDATE subscription_tokens SET expires_at = now() - interval '1 second', \
+            created_at = now() - interval '49 hours'"
+        )
+        .execute(&self.db_pool)
+        .await
+        .expect("Failed to expire the subscription tokens");
+    }
+
     pub async fn get_confirmation_links(
         &self,
         email_request: &wiremock::Request,
     ) -> ConfirmationLinks {
         let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
 
-        let get_link = |s: &str| {
-            let links: Vec<_> = linkify::LinkFinder::new()
-                .links(s)
-                .filter(|l| *l.kind() == linkify::LinkKind::Url)
-                .collect();
-            assert_eq!(links.len(), 1);
-            let raw_link = links[0].as_str().to_owned();
-            let mut confirmation_link = reqwest::Url::parse(&raw_link).unwrap();
-            assert_eq!(confirmation_link.host_str().unwrap(), "127.0.0.1");
-            confirmation_link
-                .set_port(Some(self.port))
-                .expect("Failed to set port");
-            confirmation_link
-        };
-
         ConfirmationLinks {
-            html: get_link(body["HtmlBody"].as_str().unwrap()),
-            plain_text: get_link(body["TextBody"].as_str().unwrap()),
+            html: self.get_link(body["HtmlBody"].as_str().unwrap()),
+            plain_text: self.get_link(body["TextBody"].as_str().unwrap()),
         }
     }
+
+    /// Issues go out through Postmark's batch endpoint: one JSON message per recipient,
+    /// in the order they were sent
+    pub async fn delivered_issues(&self) -> Vec<serde_json::Value> {
+        self.email_server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .filter(|request| request.url.path() == "/email/batch")
+            .flat_map(|request| {
+                serde_json::from_slice::<Vec<serde_json::Value>>(&request.body).unwrap()
+            })
+            .collect()
+    }
+
+    pub async fn get_unsubscribe_links(&self, issue: &serde_json::Value) -> UnsubscribeLinks {
+        UnsubscribeLinks {
+            html: self.get_link(issue["HtmlBody"].as_str().unwrap()),
+            plain_text: self.get_link(issue["TextBody"].as_str().unwrap()),
+        }
+    }
+
+    /// Extract the only link of an email body, pointing it to the application under test
+    fn get_link(&self, s: &str) -> reqwest::Url {
+        let links: Vec<_> = linkify::LinkFinder::new()
+            .links(s)
+            .filter(|l| *l.kind() == linkify::LinkKind::Url)
+            .collect();
+        assert_eq!(links.len(), 1);
+        let raw_link = links[0].as_str().to_owned();
+        let mut link = reqwest::Url::parse(&raw_link).unwrap();
+        assert_eq!(link.host_str().unwrap(), "127.0.0.1");
+        link.set_port(Some(self.port)).expect("Failed to set port");
+        link
+    }
 }
 
 /// Configure the database for testing.
@@ -114,6 +510,7 @@ pub async fn spawn_app() -> TestApp {
     Lazy::force(&TRACING);
 
     let email_server = wiremock::MockServer::start().await;
+    let mut test_user = TestUser::generate();
 
     // Randomize the database name to avoid conflicts.
     let configuration = {
@@ -122,8 +519,22 @@ pub async fn spawn_app() -> TestApp {
         c.database.database_name = Uuid::new_v4().to_string();
         // Use a random OS port
         c.application.port = 0;
+        // Tests drive the scheduler themselves: keep the background one out of the way
+        c.application.issue_scheduler_interval_seconds = 60 * 60;
         // Point email client to the mock server
+        // Emails go to the mock Postmark server, whatever the local backend is
+        c.email_client.backend = EmailBackend::Postmark;
         c.email_client.base_url = email_server.uri();
+        // Retries are covered by the email client tests: fail fast here
+        c.email_client.max_attempts = 1;
+        // Long enough for the mocks delaying their answer on purpose, short enough to
+        // test timeouts
+        c.email_client.timeout_milliseconds = 3000;
+        c.email_client.webhook_username = Uuid::new_v4().to_string();
+        c.email_client.webhook_password = Uuid::new_v4().to_string().into();
+        // The test user is the operator created at startup
+        c.application.initial_operator_username = test_user.username.clone();
+        c.application.initial_operator_password = Some(test_user.password.clone().into());
         c
     };
 
@@ -134,12 +545,42 @@ pub async fn spawn_app() -> TestApp {
         .await
         .expect("Failed to build application");
     let application_port = application.port();
-    let _ = tokio::spawn(application.run_until_stopped());
+    tokio::spawn(application.run_until_stopped());
+
+    let db_pool = get_connection_pool(&configuration.database).await;
+    // The operator row is created at startup with its own id
+    test_user.user_id = sqlx::query_scalar!(
+        "SELECT user_id FROM users WHERE username = $1",
+        test_user.username
+    )
+    .fetch_one(&db_pool)
+    .await
+    .expect("Failed to fetch the operator created at startup.");
 
-    TestApp {
+    let api_client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .cookie_store(true)
+        .build()
+        .unwrap();
+
+    let test_app = TestApp {
         address: format!("http://127.0.0.1:{}", application_port),
         port: application_port,
-        db_pool: get_connection_pool(&configuration.database).await,
+        db_pool,
         email_server,
-    }
+        test_user,
+        api_client,
+        webhook_username: configuration.email_client.webhook_username.clone(),
+        webhook_password: configuration
+            .email_client
+            .webhook_password
+            .expose_secret()
+            .to_owned(),
+    };
+    test_app
+}
+
+pub fn assert_is_redirect_to(response: &reqwest::Response, location: &str) {
+    assert_eq!(response.status().as_u16(), 303);
+    assert_eq!(response.headers().get("Location").unwrap(), location);
 }
@@ -0,0 +1,290 @@
+use crate::helpers::{spawn_app, AcceptEveryMessage, TestApp};
+use crate::newsletters::create_confirmed_subscriber;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+async fn publish_issue(app: &TestApp) {
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn successful_deliveries_are_logged_with_the_provider_message_id() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(serde_json::json!([{
+                "ErrorCode": 0,
+                "Message": "OK",
+                "MessageID": "b7bc2f4a-e38e-4336-af7d-e6c392c2f817",
+            }])),
+        )
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    publish_issue(&app).await;
+    app.wait_for_pending_deliveries().await;
+
+    // Assert
+    let delivery = sqlx::query!(
+        "SELECT subscriber_email, status, n_attempts, provider_message_id FROM issue_deliveries"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("Failed to fetch the delivery log");
+    assert_eq!(delivery.subscriber_email, "felixo@gmail.com");
+    assert_eq!(delivery.status, "sent");
+    assert_eq!(delivery.n_attempts, 1);
+    assert_eq!(
+        delivery.provider_message_id.as_deref(),
+        Some("b7bc2f4a-e38e-4336-af7d-e6c392c2f817")
+    );
+}
+
+#[tokio::test]
+async fn failed_attempts_are_logged_with_the_last_error() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    publish_issue(&app).await;
+
+    // Assert - the first attempt is over once the task has been postponed
+    for _ in 0..50 {
+        let delivery = sqlx::query!("SELECT status, n_attempts, last_error FROM issue_deliveries")
+            .fetch_one(&app.db_pool)
+            .await
+            .expect("Failed to fetch the delivery log");
+        if delivery.n_attempts > 0 {
+            assert_eq!(delivery.status, "pending");
+            assert!(delivery.last_error.unwrap().contains("500"));
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    panic!("The delivery has not been attempted in time.");
+}
+
+#[tokio::test]
+async fn the_report_counts_the_deliveries_of_each_issue() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(AcceptEveryMessage)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    publish_issue(&app).await;
+    app.wait_for_pending_deliveries().await;
+    app.login().await;
+
+    // Act
+    let response = app.get_issues_report().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        report,
+        serde_json::json!([{
+            "newsletter_issue_id": report[0]["newsletter_issue_id"],
+            "title": "Newsletter title",
+            "status": "sent",
+            "scheduled_at": null,
+            "enqueued_at": report[0]["enqueued_at"],
+            "sent": 1,
+            "failed": 0,
+            "pending": 0,
+            "skipped": 0,
+            "unknown": 0,
+        }])
+    );
+}
+
+#[tokio::test]
+async fn timed_out_deliveries_are_logged_as_unknown_and_not_sent_again() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    // Postmark may have accepted the email before the request timed out
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_delay(std::time::Duration::from_secs(60)))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    publish_issue(&app).await;
+    app.wait_for_pending_deliveries().await;
+
+    // Assert
+    let delivery = sqlx::query!("SELECT status, n_attempts, last_error FROM issue_deliveries")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the delivery log");
+    assert_eq!(delivery.status, "unknown");
+    assert_eq!(delivery.n_attempts, 1);
+    assert!(delivery.last_error.is_some());
+}
+
+#[tokio::test]
+async fn deliveries_wait_for_an_open_circuit_without_using_up_their_attempts() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    // The HTTP server and the worker share the circuit breaker: failing confirmation
+    // emails open it
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.email_server)
+        .await;
+    for i in 0..50 {
+        let health: serde_json::Value = reqwest::get(format!("{}/health_check", &app.address))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        if health["email_circuit_breaker"] == "open" {
+            break;
+        }
+        app.post_subscriptions(format!("name=le%20guin&email=ursula{}%40gmail.com", i))
+            .await;
+    }
+    let n_requests = app.email_server.received_requests().await.unwrap().len();
+
+    // Act
+    publish_issue(&app).await;
+
+    // Assert - the task is pushed back until the cooldown is over, without any attempt
+    for _ in 0..50 {
+        let task = sqlx::query!(
+            r#"SELECT n_retries, execute_after > now() + interval '5 seconds' as "deferred!"
+            FROM issue_delivery_queue"#
+        )
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the delivery task");
+        if task.deferred {
+            assert_eq!(task.n_retries, 0);
+            let delivery = sqlx::query!("SELECT status, n_attempts FROM issue_deliveries")
+                .fetch_one(&app.db_pool)
+                .await
+                .expect("Failed to fetch the delivery log");
+            assert_eq!(delivery.status, "pending");
+            assert_eq!(delivery.n_attempts, 0);
+            assert_eq!(
+                app.email_server.received_requests().await.unwrap().len(),
+                n_requests
+            );
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    panic!("The delivery task has not been deferred in time.");
+}
+
+/// Reject the message sent to `recipient`, accept the others of the batch
+struct RejectRecipient(&'static str);
+
+impl wiremock::Respond for RejectRecipient {
+    fn respond(&self, request: &wiremock::Request) -> ResponseTemplate {
+        let messages: Vec<serde_json::Value> = serde_json::from_slice(&request.body).unwrap();
+        let results: Vec<_> = messages
+            .iter()
+            .map(|message| match message["To"] == self.0 {
+                true => serde_json::json!({
+                    "ErrorCode": 406,
+                    "Message": "You tried to send to a recipient that has been marked as inactive.",
+                }),
+                false => serde_json::json!({"ErrorCode": 0, "Message": "OK"}),
+            })
+            .collect();
+        ResponseTemplate::new(200).set_body_json(results)
+    }
+}
+
+#[tokio::test]
+async fn only_the_recipients_whose_email_failed_in_a_batch_are_retried() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let confirmation_links = {
+        let _mock_guard = Mock::given(path("/email"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount_as_scoped(&app.email_server)
+            .await;
+        app.post_subscriptions("name=le%20guin&email=ursula%40gmail.com".into())
+            .await
+            .error_for_status()
+            .unwrap();
+        let email_request = app.email_server.received_requests().await.unwrap().pop();
+        app.get_confirmation_links(&email_request.unwrap()).await
+    };
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(RejectRecipient("ursula@gmail.com"))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    publish_issue(&app).await;
+
+    // Assert - both emails go out in a single batch, only the rejected one stays queued
+    for _ in 0..50 {
+        let deliveries = sqlx::query!(
+            "SELECT subscriber_email, status, n_attempts FROM issue_deliveries
+            ORDER BY subscriber_email"
+        )
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch the delivery log");
+        if deliveries.iter().all(|delivery| delivery.n_attempts > 0) {
+            assert_eq!(deliveries[0].subscriber_email, "felixo@gmail.com");
+            assert_eq!(deliveries[0].status, "sent");
+            assert_eq!(deliveries[1].subscriber_email, "ursula@gmail.com");
+            assert_eq!(deliveries[1].status, "pending");
+            let task = sqlx::query!("SELECT subscriber_email, n_retries FROM issue_delivery_queue")
+                .fetch_one(&app.db_pool)
+                .await
+                .expect("Failed to fetch the delivery task");
+            assert_eq!(task.subscriber_email, "ursula@gmail.com");
+            assert_eq!(task.n_retries, 1);
+            assert_eq!(app.delivered_issues().await.len(), 2);
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    panic!("The deliveries have not been attempted in time.");
+}
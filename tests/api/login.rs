@@ -0,0 +1,90 @@
+use crate::helpers::{assert_is_redirect_to, spawn_app};
+use secrecy::SecretString;
+use zero2prod::configuration::get_configuration;
+use zero2prod::session_store::delete_expired_sessions;
+use zero2prod::startup::Application;
+
+#[tokio::test]
+async fn an_error_flash_message_is_set_on_failure() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act - Part 1 - Try to login
+    let login_body = serde_json::json!({
+        "username": "random-username",
+        "password": "random-password"
+    });
+    let response = app.post_login(&login_body).await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/login");
+
+    // Act - Part 2 - Follow the redirect
+    let html_page = app.get_login_html().await;
+    assert!(html_page.contains("<p><i>Authentication failed.</i></p>"));
+
+    // Act - Part 3 - Reload the login page
+    let html_page = app.get_login_html().await;
+    assert!(!html_page.contains("Authentication failed."));
+}
+
+#[tokio::test]
+async fn redirect_to_admin_dashboard_after_login_success() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act - Part 1 - Login
+    let login_body = serde_json::json!({
+        "username": &app.test_user.username,
+        "password": &app.test_user.password
+    });
+    let response = app.post_login(&login_body).await;
+    assert_is_redirect_to(&response, "/admin/dashboard");
+
+    // Act - Part 2 - Follow the redirect
+    let html_page = app.get_admin_dashboard_html().await;
+    assert!(html_page.contains(&format!("Welcome {}", app.test_user.username)));
+
+    // The session state lives on the server
+    let n_sessions = sqlx::query_scalar!(r#"SELECT COUNT(*) as "count!" FROM sessions"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to count sessions");
+    assert_eq!(n_sessions, 1);
+}
+
+#[tokio::test]
+async fn expired_sessions_are_deleted() {
+    // Arrange
+    let app = spawn_app().await;
+    app.login().await;
+    sqlx::query!(
+        "INSERT INTO sessions (session_key, session_state, expires_at) \
+        VALUES ('expired', '{}', now() - interval '1 second')"
+    )
+    .execute(&app.db_pool)
+    .await
+    .expect("Failed to insert an expired session");
+
+    // Act
+    let deleted_sessions = delete_expired_sessions(&app.db_pool).await.unwrap();
+
+    // Assert - the session of the logged-in operator is still there
+    assert_eq!(deleted_sessions, 1);
+    let html_page = app.get_admin_dashboard_html().await;
+    assert!(html_page.contains(&format!("Welcome {}", app.test_user.username)));
+}
+
+#[tokio::test]
+async fn the_application_does_not_start_without_a_long_enough_hmac_secret() {
+    // Arrange
+    let mut configuration = get_configuration().expect("Failed to read configuration.");
+    configuration.application.port = 0;
+    configuration.application.hmac_secret = SecretString::from("too-short");
+
+    // Act
+    let outcome = Application::build(configuration).await;
+
+    // Assert
+    assert!(outcome.is_err());
+}
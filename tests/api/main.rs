@@ -1,5 +1,16 @@
+mod admin_dashboard;
+mod change_password;
 mod health_check;
 mod helpers;
+mod issue_deliveries;
+mod login;
+mod metrics;
+mod newsletter_drafts;
+mod newsletters;
+mod scheduled_newsletters;
+mod subscription_sweeper;
 mod subscriptions;
 
 mod subscriptions_confirm;
+mod subscriptions_unsubscribe;
+mod webhooks;
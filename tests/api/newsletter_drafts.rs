@@ -0,0 +1,178 @@
+use crate::helpers::{assert_is_redirect_to, spawn_app, AcceptEveryMessage, TestApp};
+use crate::newsletters::create_confirmed_subscriber;
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+/// Save a draft through the admin API, returning its id
+async fn create_draft(app: &TestApp) -> String {
+    let response = app
+        .post_draft(&serde_json::json!({
+            "title": "Draft for {{ name }}",
+            "content": {
+                "text": "Hello {{ name }}",
+                "html": "<p>Hello {{ name }}</p>",
+            }
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    body["newsletter_issue_id"].as_str().unwrap().to_owned()
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_create_a_draft() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app
+        .post_draft(&serde_json::json!({
+            "title": "Draft title",
+            "content": {"markdown": "Draft body"}
+        }))
+        .await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn drafts_are_not_delivered() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.login().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let issue_id = create_draft(&app).await;
+    app.wait_for_pending_deliveries().await;
+
+    // Assert
+    assert_eq!(app.issue_status(&issue_id).await, "draft");
+}
+
+#[tokio::test]
+async fn drafts_can_be_edited_and_previewed() {
+    // Arrange
+    let app = spawn_app().await;
+    app.login().await;
+    let issue_id = create_draft(&app).await;
+
+    // Act
+    let response = app
+        .put_draft(
+            &issue_id,
+            &serde_json::json!({
+                "title": "Edited title",
+                "content": {"markdown": "Edited for **{{ name }}**"}
+            }),
+        )
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+    let preview = app.get_issue_preview(&issue_id).await;
+
+    // Assert
+    assert_eq!(preview.status().as_u16(), 200);
+    assert_eq!(preview.headers()["Content-Security-Policy"], "sandbox");
+    let html = preview.text().await.unwrap();
+    assert!(html.contains("<h1>Edited title</h1>"));
+    assert!(html.contains(&format!("<strong>{}</strong>", app.test_user.username)));
+    assert!(html.contains("Unsubscribe"));
+}
+
+#[tokio::test]
+async fn a_test_copy_is_sent_to_a_single_address_only() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.login().await;
+    let issue_id = create_draft(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_test_issue(
+            &issue_id,
+            &serde_json::json!({"email": "editor@example.com"}),
+        )
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["To"], "editor@example.com");
+    assert_eq!(
+        body["Subject"],
+        format!("[Test] Draft for {}", app.test_user.username)
+    );
+    assert_eq!(app.issue_status(&issue_id).await, "draft");
+}
+
+#[tokio::test]
+async fn a_test_copy_to_an_invalid_address_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    app.login().await;
+    let issue_id = create_draft(&app).await;
+
+    // Act
+    let response = app
+        .post_test_issue(&issue_id, &serde_json::json!({"email": "not-an-email"}))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn sent_drafts_go_through_sending_to_sent_and_are_frozen() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.login().await;
+    let issue_id = create_draft(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(AcceptEveryMessage)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act - Part 1 - Send the draft
+    let response = app.post_send_issue(&issue_id).await;
+    app.wait_for_pending_deliveries().await;
+
+    // Assert - Part 1
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(app.issue_status(&issue_id).await, "sent");
+
+    // Act - Part 2 - The issue can no longer be changed nor sent again
+    let edit = app
+        .put_draft(
+            &issue_id,
+            &serde_json::json!({"title": "Too late", "content": {"markdown": "Too late"}}),
+        )
+        .await;
+    let send = app.post_send_issue(&issue_id).await;
+
+    // Assert - Part 2
+    assert_eq!(edit.status().as_u16(), 409);
+    assert_eq!(send.status().as_u16(), 409);
+}
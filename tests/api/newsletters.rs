@@ -0,0 +1,565 @@
+use crate::helpers::{spawn_app, AcceptEveryMessage, ConfirmationLinks, TestApp, TestUser};
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+/// Use the public API of the application under test to create an unconfirmed subscriber.
+pub async fn create_unconfirmed_subscriber(app: &TestApp) -> ConfirmationLinks {
+    let body = "name=zasha%20felixo&email=felixo%40gmail.com";
+
+    // Scoped mock: it is only active until the guard is dropped at the end of this function,
+    // so it does not interfere with the expectations of the test itself.
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .named("Create unconfirmed subscriber")
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_subscriptions(body.into())
+        .await
+        .error_for_status()
+        .unwrap();
+
+    let email_request = &app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    app.get_confirmation_links(email_request).await
+}
+
+pub async fn create_confirmed_subscriber(app: &TestApp) {
+    let confirmation_link = create_unconfirmed_subscriber(app).await;
+    reqwest::get(confirmation_link.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn newsletters_are_not_delivered_to_unconfirmed_subscribers() {
+    // Arrange
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber(&app).await;
+
+    // No request should reach the email provider
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    let response = app.post_newsletters(newsletter_request_body).await;
+    app.wait_for_pending_deliveries().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    // Mock verifies on drop that we haven't sent the newsletter email
+}
+
+#[tokio::test]
+async fn newsletters_are_delivered_to_confirmed_subscribers() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(AcceptEveryMessage)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    let response = app.post_newsletters(newsletter_request_body).await;
+    app.wait_for_pending_deliveries().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    // Mock verifies on drop that we have sent the newsletter email
+}
+
+#[tokio::test]
+async fn markdown_issues_are_delivered_as_sanitized_html_and_plain_text() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(AcceptEveryMessage)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "markdown": "Some **news** for you.<script>alert('pwned')</script>",
+        }
+    });
+    let response = app.post_newsletters(newsletter_request_body).await;
+    app.wait_for_pending_deliveries().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let body = app.delivered_issues().await.pop().unwrap();
+    let html = body["HtmlBody"].as_str().unwrap();
+    assert!(html.contains("<h1>Newsletter title</h1>"));
+    assert!(html.contains("<strong>news</strong>"));
+    assert!(!html.contains("<script>"));
+    // The unsubscribe footer is part of the document
+    assert!(html.find("Unsubscribe").unwrap() < html.find("</body>").unwrap());
+    let text = body["TextBody"].as_str().unwrap();
+    assert!(text.starts_with("Newsletter title\n\nSome news for you."));
+}
+
+#[tokio::test]
+async fn merge_tags_are_rendered_for_each_recipient() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(AcceptEveryMessage)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let newsletter_request_body = serde_json::json!({
+        "title": "News for {{ name }}",
+        "content": {
+            "text": "Hi {{ name }}, from {{ city | Paris }}. Leave: {{ unsubscribe_url }}",
+            "html": "<p>Hi {{ name }}, from {{ city | Paris }}.</p>",
+        }
+    });
+    let response = app.post_newsletters(newsletter_request_body).await;
+    app.wait_for_pending_deliveries().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let body = app.delivered_issues().await.pop().unwrap();
+    assert_eq!(body["Subject"], "News for zasha felixo");
+    let html = body["HtmlBody"].as_str().unwrap();
+    assert!(html.starts_with("<p>Hi zasha felixo, from Paris.</p>"));
+    let text = body["TextBody"].as_str().unwrap();
+    assert!(text.starts_with("Hi zasha felixo, from Paris. Leave: http://127.0.0.1"));
+    assert!(text.contains("/subscriptions/unsubscribe?token="));
+}
+
+#[tokio::test]
+async fn merge_tags_are_rendered_in_markdown_links() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(AcceptEveryMessage)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {"markdown": "Hi {{ name }}! [unsubscribe]({{ unsubscribe_url }})"}
+    });
+    let response = app.post_newsletters(newsletter_request_body).await;
+    app.wait_for_pending_deliveries().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let unsubscribe_token = sqlx::query_scalar!("SELECT unsubscribe_token FROM unsubscribe_tokens")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the unsubscribe token");
+    let unsubscribe_url = format!(
+        "http://127.0.0.1/subscriptions/unsubscribe?token={}",
+        unsubscribe_token
+    );
+    let issue = app.delivered_issues().await.pop().unwrap();
+    let html = issue["HtmlBody"].as_str().unwrap();
+    assert!(html.contains("Hi zasha felixo!"));
+    assert!(html.contains(&format!(r#"<a href="{}""#, unsubscribe_url)));
+    let text = issue["TextBody"].as_str().unwrap();
+    assert!(text.contains(&format!("unsubscribe ({})", unsubscribe_url)));
+}
+
+#[tokio::test]
+async fn newsletters_returns_400_for_invalid_data() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_cases = vec![
+        (
+            serde_json::json!({
+                "content": {
+                    "text": "Newsletter body as plain text",
+                    "html": "<p>Newsletter body as HTML</p>",
+                }
+            }),
+            "missing title",
+        ),
+        (
+            serde_json::json!({"title": "Newsletter!"}),
+            "missing content",
+        ),
+        (
+            serde_json::json!({"title": "Newsletter!", "content": {"html": "<p>Only HTML</p>"}}),
+            "incomplete content",
+        ),
+    ];
+
+    for (invalid_body, error_message) in test_cases {
+        // Act
+        let response = app.post_newsletters(invalid_body).await;
+
+        // Assert
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not fail with 400 Bad Request when the payload was {}.",
+            error_message
+        );
+    }
+}
+
+#[tokio::test]
+async fn publishing_a_newsletter_persists_the_issue_and_enqueues_deliveries() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    // The provider keeps failing: the delivery task must stay in the queue
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    let response = app.post_newsletters(newsletter_request_body).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let issue = sqlx::query!("SELECT newsletter_issue_id, title FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved newsletter issue");
+    assert_eq!(issue.title, "Newsletter title");
+
+    let task = sqlx::query!(
+        "SELECT subscriber_email FROM issue_delivery_queue WHERE newsletter_issue_id = $1",
+        issue.newsletter_issue_id
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("Failed to fetch the delivery task");
+    assert_eq!(task.subscriber_email, "felixo@gmail.com");
+}
+
+#[tokio::test]
+async fn issues_published_to_an_empty_list_are_sent_right_away() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {"markdown": "Nobody will read this"}
+        }))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "sent");
+    let issue_id = body["newsletter_issue_id"].as_str().unwrap();
+    assert_eq!(app.issue_status(issue_id).await, "sent");
+}
+
+#[tokio::test]
+async fn newsletter_creation_is_idempotent() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(AcceptEveryMessage)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act - Publish the same issue twice with the same key
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+    let idempotency_key = uuid::Uuid::new_v4().to_string();
+    let first_response = app
+        .post_newsletters_with_idempotency_key(newsletter_request_body.clone(), &idempotency_key)
+        .await;
+    let second_response = app
+        .post_newsletters_with_idempotency_key(newsletter_request_body, &idempotency_key)
+        .await;
+    app.wait_for_pending_deliveries().await;
+
+    // Assert
+    assert_eq!(first_response.status().as_u16(), 200);
+    assert_eq!(second_response.status().as_u16(), 200);
+    let n_issues = sqlx::query_scalar!(r#"SELECT COUNT(*) as "count!" FROM newsletter_issues"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to count newsletter issues");
+    assert_eq!(n_issues, 1);
+    // Mock verifies on drop that we have sent the newsletter email once
+}
+
+async fn count_issues(app: &TestApp) -> i64 {
+    sqlx::query_scalar!(r#"SELECT COUNT(*) as "count!" FROM newsletter_issues"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to count newsletter issues")
+}
+
+#[tokio::test]
+async fn idempotency_keys_are_scoped_to_the_operator() {
+    // Arrange
+    let app = spawn_app().await;
+    let other_user = TestUser::generate();
+    other_user.store(&app.db_pool).await;
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {"markdown": "Newsletter body"}
+    });
+    let idempotency_key = uuid::Uuid::new_v4().to_string();
+
+    // Act - Two operators happen to use the same key
+    let first_response = app
+        .post_newsletters_with_idempotency_key(newsletter_request_body.clone(), &idempotency_key)
+        .await;
+    let second_response = app
+        .api_client
+        .post(format!("{}/newsletters", &app.address))
+        .basic_auth(&other_user.username, Some(&other_user.password))
+        .header("Idempotency-Key", &idempotency_key)
+        .json(&newsletter_request_body)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(first_response.status().as_u16(), 200);
+    assert_eq!(second_response.status().as_u16(), 200);
+    assert_eq!(count_issues(&app).await, 2);
+}
+
+#[tokio::test]
+async fn idempotency_keys_are_scoped_to_the_endpoint() {
+    // Arrange
+    let app = spawn_app().await;
+    let idempotency_key = uuid::Uuid::new_v4().to_string();
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions_with_idempotency_key(
+        "name=zasha%20felixo&email=felixo%40gmail.com".into(),
+        &idempotency_key,
+    )
+    .await
+    .error_for_status()
+    .unwrap();
+
+    // Act - The same key is reused on another endpoint
+    let response = app
+        .post_newsletters_with_idempotency_key(
+            serde_json::json!({
+                "title": "Newsletter title",
+                "content": {"markdown": "Newsletter body"}
+            }),
+            &idempotency_key,
+        )
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert!(body["newsletter_issue_id"].is_string());
+    assert_eq!(count_issues(&app).await, 1);
+}
+
+#[tokio::test]
+async fn newsletters_returns_400_for_an_invalid_idempotency_key() {
+    // Arrange
+    let app = spawn_app().await;
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    });
+
+    // Act
+    let response = app
+        .post_newsletters_with_idempotency_key(newsletter_request_body, &"a".repeat(60))
+        .await;
+
+    // Assert
+    assert_eq!(400, response.status().as_u16());
+    assert_eq!(count_issues(&app).await, 0);
+}
+
+#[tokio::test]
+async fn requests_missing_authorization_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .json(&serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(
+        r#"Basic realm="publish""#,
+        response.headers()["WWW-Authenticate"]
+    );
+}
+
+#[tokio::test]
+async fn non_existing_user_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    // Random credentials
+    let username = uuid::Uuid::new_v4().to_string();
+    let password = uuid::Uuid::new_v4().to_string();
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .basic_auth(username, Some(password))
+        .json(&serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(
+        r#"Basic realm="publish""#,
+        response.headers()["WWW-Authenticate"]
+    );
+}
+
+#[tokio::test]
+async fn invalid_password_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    let username = &app.test_user.username;
+    // Random password
+    let password = uuid::Uuid::new_v4().to_string();
+    assert_ne!(app.test_user.password, password);
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .basic_auth(username, Some(password))
+        .json(&serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(
+        r#"Basic realm="publish""#,
+        response.headers()["WWW-Authenticate"]
+    );
+}
+
+#[tokio::test]
+async fn the_credentials_once_seeded_by_the_migrations_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .basic_auth("admin", Some("everythinghastostartsomewhere"))
+        .json(&serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(401, response.status().as_u16());
+}
@@ -0,0 +1,156 @@
+use crate::helpers::{assert_is_redirect_to, spawn_app, TestApp};
+use crate::newsletters::create_confirmed_subscriber;
+use uuid::Uuid;
+use zero2prod::issue_scheduler::enqueue_due_issues;
+
+/// Publish an issue scheduled for tomorrow, returning its id
+async fn publish_scheduled_issue(app: &TestApp) -> String {
+    let scheduled_at = chrono::Utc::now() + chrono::Duration::days(1);
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            },
+            "scheduled_at": scheduled_at.to_rfc3339(),
+        }))
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    body["newsletter_issue_id"].as_str().unwrap().to_owned()
+}
+
+async fn count_delivery_tasks(app: &TestApp) -> i64 {
+    sqlx::query_scalar!(r#"SELECT COUNT(*) as "count!" FROM issue_delivery_queue"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to count delivery tasks")
+}
+
+/// Move the send date of every scheduled issue into the past
+async fn make_scheduled_issues_due(app: &TestApp) {
+    sqlx::query!(
+        "UPDATE newsletter_issues SET scheduled_at = now() - interval '1 second' \
+        WHERE scheduled_at IS NOT NULL"
+    )
+    .execute(&app.db_pool)
+    .await
+    .expect("Failed to update the scheduled issues");
+}
+
+#[tokio::test]
+async fn scheduled_issues_are_enqueued_once_when_due() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    // Act - Part 1 - Publish an issue for tomorrow
+    publish_scheduled_issue(&app).await;
+
+    // Assert - Part 1 - Nothing is enqueued before the date
+    assert_eq!(enqueue_due_issues(&app.db_pool).await.unwrap(), 0);
+    assert_eq!(count_delivery_tasks(&app).await, 0);
+
+    // Act - Part 2 - The date is reached
+    make_scheduled_issues_due(&app).await;
+    let first_run = enqueue_due_issues(&app.db_pool).await.unwrap();
+    let second_run = enqueue_due_issues(&app.db_pool).await.unwrap();
+
+    // Assert - Part 2
+    assert_eq!(first_run, 1);
+    assert_eq!(second_run, 0);
+    assert_eq!(count_delivery_tasks(&app).await, 1);
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_reschedule_an_issue() {
+    // Arrange
+    let app = spawn_app().await;
+    let issue_id = publish_scheduled_issue(&app).await;
+
+    // Act
+    let response = app.delete_issue_schedule(&issue_id).await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn scheduled_issues_can_be_rescheduled_before_the_send() {
+    // Arrange
+    let app = spawn_app().await;
+    let issue_id = publish_scheduled_issue(&app).await;
+    app.login().await;
+    let scheduled_at = "2099-01-01T08:00:00Z";
+
+    // Act
+    let response = app
+        .put_issue_schedule(
+            &issue_id,
+            &serde_json::json!({ "scheduled_at": scheduled_at }),
+        )
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query_scalar!("SELECT scheduled_at FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.unwrap().to_rfc3339(), "2099-01-01T08:00:00+00:00");
+}
+
+#[tokio::test]
+async fn cancelled_issues_are_never_enqueued() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let issue_id = publish_scheduled_issue(&app).await;
+    app.login().await;
+
+    // Act
+    let response = app.delete_issue_schedule(&issue_id).await;
+    make_scheduled_issues_due(&app).await;
+    enqueue_due_issues(&app.db_pool).await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 204);
+    assert_eq!(count_delivery_tasks(&app).await, 0);
+}
+
+#[tokio::test]
+async fn issues_cannot_be_rescheduled_once_enqueued() {
+    // Arrange
+    let app = spawn_app().await;
+    let issue_id = publish_scheduled_issue(&app).await;
+    make_scheduled_issues_due(&app).await;
+    enqueue_due_issues(&app.db_pool).await.unwrap();
+    app.login().await;
+
+    // Act
+    let reschedule = app
+        .put_issue_schedule(
+            &issue_id,
+            &serde_json::json!({ "scheduled_at": "2099-01-01T08:00:00Z" }),
+        )
+        .await;
+    let cancel = app.delete_issue_schedule(&issue_id).await;
+
+    // Assert
+    assert_eq!(reschedule.status().as_u16(), 409);
+    assert_eq!(cancel.status().as_u16(), 409);
+}
+
+#[tokio::test]
+async fn rescheduling_an_unknown_issue_returns_404() {
+    // Arrange
+    let app = spawn_app().await;
+    app.login().await;
+
+    // Act
+    let response = app.delete_issue_schedule(&Uuid::new_v4().to_string()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 404);
+}
@@ -0,0 +1,171 @@
+use crate::helpers::spawn_app;
+use std::time::Duration;
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod::subscription_sweeper::{sweep, SweepOutcome};
+
+const RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Insert a subscriber who signed up `age` ago, with a token expiring `token_expires_in`
+/// from now (in the past when negative)
+async fn insert_subscriber(
+    pool: &sqlx::PgPool,
+    email: &str,
+    status: &str,
+    age: chrono::Duration,
+    token_expires_in: chrono::Duration,
+) {
+    let subscriber_id = Uuid::new_v4();
+    let subscribed_at = chrono::Utc::now() - age;
+    sqlx::query!(
+        "INSERT INTO subscriptions (id, email, name, subscribed_at, status) VALUES ($1, $2, $3, $4, $5)",
+        subscriber_id,
+        email,
+        "le guin",
+        subscribed_at,
+        status
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+
+    let expires_at = chrono::Utc::now() + token_expires_in;
+    sqlx::query!(
+        "INSERT INTO subscription_tokens (subscription_token, subscriber_id, created_at, expires_at) \
+        VALUES ($1, $2, $3, $4)",
+        Uuid::new_v4().simple().to_string(),
+        subscriber_id,
+        subscribed_at,
+        expires_at
+    )
+    .execute(pool)
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn sweep_deletes_long_expired_tokens_and_stale_pending_subscribers() {
+    // Arrange
+    let app = spawn_app().await;
+    let old = chrono::Duration::days(3);
+    let recent = chrono::Duration::hours(1);
+    let long_expired = -chrono::Duration::days(2);
+    insert_subscriber(
+        &app.db_pool,
+        "stale@example.com",
+        "pending_confirmation",
+        old,
+        long_expired,
+    )
+    .await;
+    insert_subscriber(
+        &app.db_pool,
+        "recent@example.com",
+        "pending_confirmation",
+        recent,
+        long_expired,
+    )
+    .await;
+    insert_subscriber(
+        &app.db_pool,
+        "just_expired@example.com",
+        "pending_confirmation",
+        old,
+        -chrono::Duration::hours(1),
+    )
+    .await;
+    insert_subscriber(
+        &app.db_pool,
+        "valid@example.com",
+        "pending_confirmation",
+        old,
+        chrono::Duration::hours(1),
+    )
+    .await;
+    insert_subscriber(
+        &app.db_pool,
+        "confirmed@example.com",
+        "confirmed",
+        old,
+        long_expired,
+    )
+    .await;
+
+    // Act
+    let outcome = sweep(&app.db_pool, RETENTION).await.unwrap();
+
+    // Assert
+    assert_eq!(
+        outcome,
+        SweepOutcome {
+            deleted_tokens: 3,
+            deleted_subscribers: 1,
+        }
+    );
+    let emails = sqlx::query_scalar!("SELECT email FROM subscriptions ORDER BY email")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    // Recent signups keep their chance to subscribe again, a token within the retention
+    // window (valid or not) keeps its owner and confirmed readers are never touched.
+    assert_eq!(
+        emails,
+        vec![
+            "confirmed@example.com",
+            "just_expired@example.com",
+            "recent@example.com",
+            "valid@example.com"
+        ]
+    );
+    let n_tokens = sqlx::query_scalar!(r#"SELECT COUNT(*) as "count!" FROM subscription_tokens"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(n_tokens, 2);
+}
+
+#[tokio::test]
+async fn sweep_keeps_recently_expired_confirmation_links_answering_410() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request).await;
+    app.expire_subscription_tokens().await;
+
+    // Act
+    sweep(&app.db_pool, RETENTION).await.unwrap();
+
+    // Assert
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+    assert_eq!(410, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn sweep_keeps_fresh_confirmation_links_working() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request).await;
+
+    // Act
+    sweep(&app.db_pool, RETENTION).await.unwrap();
+
+    // Assert
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+    assert_eq!(200, response.status().as_u16());
+}
@@ -101,6 +101,231 @@ async fn subscribe_returns_a_400_when_fields_are_present_but_invalid() {
     }
 }
 
+#[tokio::test]
+async fn subscribe_accepts_a_json_body() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_subscriptions_json(&serde_json::json!({
+            "name": "zasha felixo",
+            "email": "felixo@gmail.com"
+        }))
+        .await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT email, name, status FROM subscriptions",)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.email, "felixo@gmail.com");
+    assert_eq!(saved.name, "zasha felixo");
+    assert_eq!(saved.status, "pending_confirmation");
+}
+
+#[tokio::test]
+async fn subscribe_validates_json_bodies_like_forms() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_cases = [
+        (
+            serde_json::json!({"name": "", "email": "felixo@gmail.com"}),
+            "empty name",
+        ),
+        (
+            serde_json::json!({"name": "zasha felixo", "email": "definitely-not-an-email"}),
+            "invalid email",
+        ),
+        (serde_json::json!({"name": "zasha felixo"}), "missing email"),
+    ];
+
+    for (body, description) in test_cases {
+        // Act
+        let response = app.post_subscriptions_json(&body).await;
+
+        // Assert
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not return a 400 Bad Request when the payload was {}",
+            description
+        );
+    }
+}
+
+#[tokio::test]
+async fn subscribe_rejects_unsupported_content_types_with_a_415() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_cases = [
+        (Some("text/plain"), "plain text"),
+        (Some("application/xml"), "XML"),
+        (None, "no content type"),
+    ];
+
+    for (content_type, description) in test_cases {
+        let mut request = app
+            .api_client
+            .post(format!("{}/subscriptions", &app.address))
+            .body("name=zasha%20felixo&email=felixo%40gmail.com");
+        if let Some(content_type) = content_type {
+            request = request.header("Content-Type", content_type);
+        }
+
+        // Act
+        let response = request.send().await.expect("Failed to execute request.");
+
+        // Assert
+        assert_eq!(
+            415,
+            response.status().as_u16(),
+            "The API did not return a 415 Unsupported Media Type for {}",
+            description
+        );
+    }
+}
+
+#[tokio::test]
+async fn subscribe_explains_why_the_data_was_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app
+        .post_subscriptions("name=zasha%20felixo&email=definitely-not-an-email".into())
+        .await;
+
+    // Assert
+    assert_eq!(400, response.status().as_u16());
+    let body = response.text().await.unwrap();
+    assert!(body.contains("definitely-not-an-email"));
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_problem_document_listing_the_invalid_fields_to_json_clients() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app
+        .post_subscriptions_accepting_json("name=&email=definitely-not-an-email".into())
+        .await;
+
+    // Assert
+    assert_eq!(400, response.status().as_u16());
+    assert_eq!(
+        response.headers()["Content-Type"],
+        "application/problem+json"
+    );
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["status"], 400);
+    assert_eq!(problem["type"], "/problems/invalid-subscriber");
+    assert!(problem["title"].is_string());
+    let errors = problem["errors"].as_array().unwrap();
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0]["pointer"], "#/name");
+    assert_eq!(errors[1]["pointer"], "#/email");
+    assert!(errors[1]["detail"]
+        .as_str()
+        .unwrap()
+        .contains("definitely-not-an-email"));
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_problem_document_for_a_missing_field_to_json_clients() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app
+        .post_subscriptions_accepting_json("name=zasha%20felixo".into())
+        .await;
+
+    // Assert
+    assert_eq!(400, response.status().as_u16());
+    assert_eq!(
+        response.headers()["Content-Type"],
+        "application/problem+json"
+    );
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["type"], "about:blank");
+    assert_eq!(problem["title"], "Bad Request");
+    assert!(problem["detail"].as_str().unwrap().contains("email"));
+}
+
+#[tokio::test]
+async fn subscribe_keeps_plain_error_responses_for_form_posts() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app
+        .post_subscriptions("name=&email=felixo%40gmail.com".into())
+        .await;
+
+    // Assert
+    assert_eq!(400, response.status().as_u16());
+    assert_ne!(
+        response
+            .headers()
+            .get("Content-Type")
+            .and_then(|h| h.to_str().ok()),
+        Some("application/problem+json")
+    );
+    assert_eq!(
+        response.text().await.unwrap(),
+        " is not a valid subscriber name."
+    );
+}
+
+#[tokio::test]
+async fn subscribe_does_not_leak_internal_errors_in_problem_documents() {
+    // Arrange
+    let app = spawn_app().await;
+    sqlx::query!("ALTER TABLE subscription_tokens DROP COLUMN subscription_token;",)
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // Act
+    let response = app
+        .post_subscriptions_accepting_json("name=zasha%20felixo&email=felixo%40gmail.com".into())
+        .await;
+
+    // Assert
+    assert_eq!(500, response.status().as_u16());
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["status"], 500);
+    assert_eq!(problem["title"], "Internal Server Error");
+    assert!(problem.get("detail").is_none());
+}
+
+#[tokio::test]
+async fn subscribe_fails_if_there_is_a_fatal_database_error() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=zasha%20felixo&email=felixo%40gmail.com";
+    // Sabotage the database
+    sqlx::query!("ALTER TABLE subscription_tokens DROP COLUMN subscription_token;",)
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 500);
+}
+
 #[tokio::test]
 async fn subscribe_sends_a_confirmation_email_for_valid_data() {
     // Arrange
@@ -123,3 +348,310 @@ async fn subscribe_sends_a_confirmation_email_for_valid_data() {
 
     assert_eq!(confirmation_links.html, confirmation_links.plain_text);
 }
+
+#[tokio::test]
+async fn the_confirmation_email_greets_the_subscriber_by_name() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=zasha%20%26%20co&email=felixo%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_subscriptions(body.into()).await;
+
+    // Assert
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    // The name is escaped in the HTML body only
+    assert!(body["HtmlBody"]
+        .as_str()
+        .unwrap()
+        .contains("zasha &amp; co"));
+    assert!(body["TextBody"].as_str().unwrap().contains("zasha & co"));
+}
+
+#[tokio::test]
+async fn subscribe_is_idempotent() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=zasha%20felixo&email=felixo%40gmail.com";
+    let idempotency_key = uuid::Uuid::new_v4().to_string();
+
+    // Only one confirmation email must go out
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act - Submit the form twice with the same key
+    let first_response = app
+        .post_subscriptions_with_idempotency_key(body.into(), &idempotency_key)
+        .await;
+    let second_response = app
+        .post_subscriptions_with_idempotency_key(body.into(), &idempotency_key)
+        .await;
+
+    // Assert
+    assert_eq!(200, first_response.status().as_u16());
+    assert_eq!(first_response.status(), second_response.status());
+    assert_eq!(
+        first_response.bytes().await.unwrap(),
+        second_response.bytes().await.unwrap()
+    );
+    // Mock verifies on drop that we have sent the confirmation email once
+}
+
+#[tokio::test]
+async fn concurrent_subscribe_requests_are_handled_gracefully() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=zasha%20felixo&email=felixo%40gmail.com";
+    let idempotency_key = uuid::Uuid::new_v4().to_string();
+
+    // Setting a long delay to ensure that the second request
+    // arrives before the first one completes
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_delay(std::time::Duration::from_secs(2)))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act - Submit two requests concurrently
+    let response1 = app.post_subscriptions_with_idempotency_key(body.into(), &idempotency_key);
+    let response2 = app.post_subscriptions_with_idempotency_key(body.into(), &idempotency_key);
+    let (response1, response2) = tokio::join!(response1, response2);
+
+    // Assert
+    assert_eq!(200, response1.status().as_u16());
+    assert_eq!(response1.status(), response2.status());
+    // Mock verifies on drop that we have sent the confirmation email once
+}
+
+#[tokio::test]
+async fn a_subscription_whose_email_failed_can_be_retried_with_the_same_key() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=zasha%20felixo&email=felixo%40gmail.com";
+    let idempotency_key = uuid::Uuid::new_v4().to_string();
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let first_response = app
+        .post_subscriptions_with_idempotency_key(body.into(), &idempotency_key)
+        .await;
+    let second_response = app
+        .post_subscriptions_with_idempotency_key(body.into(), &idempotency_key)
+        .await;
+
+    // Assert
+    assert_eq!(500, first_response.status().as_u16());
+    // The failed request did not keep the key: the retry sends the email
+    assert_eq!(200, second_response.status().as_u16());
+
+    // The subscriber was kept, and both emails carry the same confirmation link
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let first_links = app.get_confirmation_links(&email_requests[0]).await;
+    let second_links = app.get_confirmation_links(&email_requests[1]).await;
+    assert_eq!(first_links.html, second_links.html);
+    let response = reqwest::get(second_links.html).await.unwrap();
+    assert_eq!(200, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn concurrent_first_time_subscriptions_for_the_same_email_both_succeed() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=zasha%20felixo&email=felixo%40gmail.com";
+
+    // Slow answers keep both requests in flight at the same time
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_delay(std::time::Duration::from_secs(1)))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    // Act - No idempotency key: both requests are processed
+    let response1 = app.post_subscriptions(body.into());
+    let response2 = app.post_subscriptions(body.into());
+    let (response1, response2) = tokio::join!(response1, response2);
+
+    // Assert
+    assert_eq!(200, response1.status().as_u16());
+    assert_eq!(200, response2.status().as_u16());
+    let n_subscribers = sqlx::query_scalar!(r#"SELECT COUNT(*) as "count!" FROM subscriptions"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to count subscribers");
+    assert_eq!(n_subscribers, 1);
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_400_for_an_invalid_idempotency_key() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=zasha%20felixo&email=felixo%40gmail.com";
+
+    // Act
+    let response = app
+        .post_subscriptions_with_idempotency_key(body.into(), &"a".repeat(60))
+        .await;
+
+    // Assert
+    assert_eq!(400, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscribing_twice_while_pending_resends_the_confirmation_email() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=zasha%20felixo&email=felixo%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let first_response = app.post_subscriptions(body.into()).await;
+    let second_response = app.post_subscriptions(body.into()).await;
+
+    // Assert
+    assert_eq!(200, first_response.status().as_u16());
+    assert_eq!(200, second_response.status().as_u16());
+
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let first_links = app.get_confirmation_links(&email_requests[0]).await;
+    let second_links = app.get_confirmation_links(&email_requests[1]).await;
+    // The pending subscriber keeps the same token: both links are valid
+    assert_eq!(first_links.html, second_links.html);
+
+    let n_subscribers = sqlx::query_scalar!(r#"SELECT COUNT(*) as "count!" FROM subscriptions"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to count subscribers");
+    assert_eq!(n_subscribers, 1);
+    // Mock verifies on drop that we have sent two confirmation emails
+}
+
+#[tokio::test]
+async fn subscribing_again_after_the_token_expired_sends_a_fresh_confirmation_link() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=zasha%20felixo&email=felixo%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.expire_subscription_tokens().await;
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let first_links = app.get_confirmation_links(&email_requests[0]).await;
+    let second_links = app.get_confirmation_links(&email_requests[1]).await;
+    assert_ne!(first_links.html, second_links.html);
+
+    let response = reqwest::get(second_links.html).await.unwrap();
+    assert_eq!(200, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscribing_again_once_confirmed_returns_a_200_without_sending_an_email() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=zasha%20felixo&email=felixo%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request).await;
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions");
+    assert_eq!(saved.status, "confirmed");
+    // Mock verifies on drop that we have not sent a second email
+}
+
+#[tokio::test]
+async fn subscribing_again_after_unsubscribing_requires_a_new_confirmation() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=zasha%20felixo&email=felixo%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    sqlx::query!(
+        "INSERT INTO subscriptions (id, email, name, subscribed_at, status)
+        VALUES ($1, 'felixo@gmail.com', 'zasha felixo', now(), 'unsubscribed')",
+        uuid::Uuid::new_v4()
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions");
+    assert_eq!(saved.status, "pending_confirmation");
+    // Mock verifies on drop that we have sent a confirmation email
+}
@@ -0,0 +1,292 @@
+use crate::helpers::{spawn_app, AcceptEveryMessage};
+use crate::newsletters::create_confirmed_subscriber;
+use wiremock::matchers::{method, path};
+use wiremock::Mock;
+
+/// Publish an issue to the (only) confirmed subscriber and return the message sent to them
+pub async fn publish_an_issue(app: &crate::helpers::TestApp) -> serde_json::Value {
+    let _mock_guard = Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(AcceptEveryMessage)
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+
+    app.post_newsletters(serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+    app.wait_for_pending_deliveries().await;
+
+    app.delivered_issues().await.pop().unwrap()
+}
+
+#[tokio::test]
+async fn unsubscribe_without_token_is_rejected_with_a_400() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::get(&format!("{}/subscriptions/unsubscribe", app.address))
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(400, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn unsubscribe_with_an_unknown_token_is_rejected_with_a_401() {
+    // Arrange
+    let app = spawn_app().await;
+    let url = format!(
+        "{}/subscriptions/unsubscribe?token=unknown-token",
+        app.address
+    );
+
+    // Act
+    let get_response = reqwest::get(&url).await.unwrap();
+    let post_response = app.post_unsubscribe(url.parse().unwrap()).await;
+
+    // Assert
+    assert_eq!(401, get_response.status().as_u16());
+    assert_eq!(401, post_response.status().as_u16());
+}
+
+#[tokio::test]
+async fn newsletter_issues_contain_an_unsubscribe_link() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    // Act
+    let issue = publish_an_issue(&app).await;
+
+    // Assert
+    let unsubscribe_links = app.get_unsubscribe_links(&issue).await;
+    assert_eq!(unsubscribe_links.html, unsubscribe_links.plain_text);
+    assert_eq!(unsubscribe_links.html.path(), "/subscriptions/unsubscribe");
+}
+
+#[tokio::test]
+async fn clicking_on_the_unsubscribe_link_asks_for_a_confirmation() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let issue = publish_an_issue(&app).await;
+    let unsubscribe_links = app.get_unsubscribe_links(&issue).await;
+
+    // Act - e.g. a link scanner, or a mail client prefetching the link
+    let response = reqwest::get(unsubscribe_links.html.clone()).await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let html_page = response.text().await.unwrap();
+    assert!(html_page.contains(r#"method="post""#));
+    assert!(html_page.contains(&format!(
+        r#"action="/subscriptions/unsubscribe?{}""#,
+        unsubscribe_links.html.query().unwrap()
+    )));
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions");
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn confirming_the_unsubscription_unsubscribes_a_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let issue = publish_an_issue(&app).await;
+    let unsubscribe_links = app.get_unsubscribe_links(&issue).await;
+
+    // Act
+    let response = app.post_unsubscribe(unsubscribe_links.html).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("You have been unsubscribed"));
+    let saved = sqlx::query!("SELECT email, status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions");
+    assert_eq!(saved.email, "felixo@gmail.com");
+    assert_eq!(saved.status, "unsubscribed");
+}
+
+#[tokio::test]
+async fn unsubscribed_readers_do_not_receive_newsletter_issues() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let issue = publish_an_issue(&app).await;
+    let unsubscribe_links = app.get_unsubscribe_links(&issue).await;
+    app.post_unsubscribe(unsubscribe_links.plain_text)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(AcceptEveryMessage)
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_newsletters(serde_json::json!({
+        "title": "Another newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+    app.wait_for_pending_deliveries().await;
+
+    // Assert
+    // Mock verifies on drop that we haven't sent the second issue
+}
+
+#[tokio::test]
+async fn newsletter_issues_carry_one_click_unsubscribe_headers() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    // Act
+    let issue = publish_an_issue(&app).await;
+
+    // Assert
+    let unsubscribe_links = app.get_unsubscribe_links(&issue).await;
+    let headers = issue["Headers"].as_array().unwrap();
+    let header_value = |name: &str| {
+        headers
+            .iter()
+            .find(|h| h["Name"] == name)
+            .and_then(|h| h["Value"].as_str())
+            .unwrap()
+            .to_owned()
+    };
+    let list_unsubscribe = header_value("List-Unsubscribe");
+    let one_click_link = reqwest::Url::parse(
+        list_unsubscribe
+            .strip_prefix('<')
+            .and_then(|v| v.strip_suffix('>'))
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(one_click_link.query(), unsubscribe_links.html.query());
+    assert_eq!(
+        header_value("List-Unsubscribe-Post"),
+        "List-Unsubscribe=One-Click"
+    );
+}
+
+#[tokio::test]
+async fn one_click_unsubscribe_unsubscribes_a_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let issue = publish_an_issue(&app).await;
+    let unsubscribe_links = app.get_unsubscribe_links(&issue).await;
+
+    // Act - No cookies, no session: this is what a mailbox provider does
+    let response = reqwest::Client::new()
+        .post(unsubscribe_links.html)
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("List-Unsubscribe=One-Click")
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions");
+    assert_eq!(saved.status, "unsubscribed");
+}
+
+#[tokio::test]
+async fn one_click_unsubscribe_accepts_multipart_bodies() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let issue = publish_an_issue(&app).await;
+    let unsubscribe_links = app.get_unsubscribe_links(&issue).await;
+
+    // Act - RFC 8058 recommends `multipart/form-data`
+    let body = "--boundary\r\n\
+        Content-Disposition: form-data; name=\"List-Unsubscribe\"\r\n\
+        \r\n\
+        One-Click\r\n\
+        --boundary--\r\n";
+    let response = reqwest::Client::new()
+        .post(unsubscribe_links.html)
+        .header("Content-Type", "multipart/form-data; boundary=boundary")
+        .body(body)
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions");
+    assert_eq!(saved.status, "unsubscribed");
+}
+
+#[tokio::test]
+async fn one_click_unsubscribe_requires_the_rfc_8058_body() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let issue = publish_an_issue(&app).await;
+    let unsubscribe_links = app.get_unsubscribe_links(&issue).await;
+    let test_cases = [
+        ("", "missing body"),
+        ("List-Unsubscribe=Yes", "wrong value"),
+    ];
+
+    for (body, description) in test_cases {
+        // Act
+        let response = reqwest::Client::new()
+            .post(unsubscribe_links.html.clone())
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+
+        // Assert
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not fail with 400 Bad Request when the payload was {}",
+            description
+        );
+    }
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions");
+    assert_eq!(saved.status, "confirmed");
+}
@@ -0,0 +1,204 @@
+use crate::helpers::{spawn_app, TestApp};
+use crate::newsletters::create_confirmed_subscriber;
+use crate::subscriptions_unsubscribe::publish_an_issue;
+use wiremock::matchers::any;
+use wiremock::{Mock, ResponseTemplate};
+
+fn bounce(kind: &str, inactive: bool) -> serde_json::Value {
+    serde_json::json!({
+        "RecordType": "Bounce",
+        "ID": 4323372036854775807u64,
+        "Type": kind,
+        "TypeCode": 1,
+        "Email": "felixo@gmail.com",
+        "Inactive": inactive,
+        "BouncedAt": "2026-10-17T10:00:00Z",
+    })
+}
+
+async fn subscriber_status(app: &TestApp) -> String {
+    sqlx::query!("SELECT status FROM subscriptions WHERE email = 'felixo@gmail.com'")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the subscriber.")
+        .status
+}
+
+#[tokio::test]
+async fn webhook_calls_without_valid_credentials_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let url = format!("{}/webhooks/postmark", &app.address);
+
+    // Act
+    let anonymous = app
+        .api_client
+        .post(&url)
+        .json(&bounce("HardBounce", true))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let wrong_password = app
+        .api_client
+        .post(&url)
+        .basic_auth(&app.webhook_username, Some("not-the-secret"))
+        .json(&bounce("HardBounce", true))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    for response in [anonymous, wrong_password] {
+        assert_eq!(response.status().as_u16(), 401);
+        assert_eq!(
+            r#"Basic realm="webhooks""#,
+            response.headers()["WWW-Authenticate"]
+        );
+    }
+    assert_eq!(subscriber_status(&app).await, "confirmed");
+}
+
+#[tokio::test]
+async fn hard_bounces_stop_future_deliveries() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_postmark_webhook(&bounce("HardBounce", true)).await;
+    app.post_newsletters(serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+    app.wait_for_pending_deliveries().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "bounced");
+}
+
+#[tokio::test]
+async fn soft_bounces_are_ignored() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    // Act
+    let response = app
+        .post_postmark_webhook(&bounce("SoftBounce", false))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "confirmed");
+}
+
+#[tokio::test]
+async fn spam_complaints_mark_the_subscriber_as_complained() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+
+    // Act - a later bounce does not hide the complaint
+    let response = app
+        .post_postmark_webhook(&serde_json::json!({
+            "RecordType": "SpamComplaint",
+            "ID": 42,
+            "Type": "SpamComplaint",
+            "Email": "felixo@gmail.com",
+            "BouncedAt": "2026-10-17T10:00:00Z",
+        }))
+        .await;
+    app.post_postmark_webhook(&bounce("HardBounce", true)).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "complained");
+}
+
+#[tokio::test]
+async fn complained_subscribers_are_not_emailed_when_subscribing_again() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.post_postmark_webhook(&serde_json::json!({
+        "RecordType": "SpamComplaint",
+        "Email": "felixo@gmail.com",
+    }))
+    .await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_subscriptions("name=zasha%20felixo&email=felixo%40gmail.com".into())
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "complained");
+}
+
+#[tokio::test]
+async fn an_old_unsubscribe_link_does_not_clear_a_complaint() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let issue = publish_an_issue(&app).await;
+    let unsubscribe_links = app.get_unsubscribe_links(&issue).await;
+    app.post_postmark_webhook(&serde_json::json!({
+        "RecordType": "SpamComplaint",
+        "Email": "felixo@gmail.com",
+    }))
+    .await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act - the reader confirms the unsubscription, then signs up again
+    app.post_unsubscribe(unsubscribe_links.html)
+        .await
+        .error_for_status()
+        .unwrap();
+    let response = app
+        .post_subscriptions("name=zasha%20felixo&email=felixo%40gmail.com".into())
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "complained");
+}
+
+#[tokio::test]
+async fn other_record_types_are_acknowledged() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app
+        .post_postmark_webhook(&serde_json::json!({
+            "RecordType": "Delivery",
+            "Recipient": "felixo@gmail.com",
+        }))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+}